@@ -1,39 +1,121 @@
 use std::ops::Deref;
 const DEFAULT_CONNECTION_TIMEOUT_SECONDS: u64 = 5;
+const DEFAULT_TLS_TIMEOUT_SECONDS: u64 = 5;
 const DEFAULT_DOWNLOAD_TIMEOUT_SECONDS: u64 = 5;
 const DEFAULT_CACHE_TIMEOUT_SECONDS: u64 = 300;
+const DEFAULT_RETRIES: u32 = 3;
 
-use clap::{Parser, ValueEnum};
+use clap::{ArgAction, Parser, ValueEnum};
 
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 
 use url::Url;
 
 use crate::DEFAULT_URL;
 
 /// retrieve and filter a list of the latest Arch Linux mirrors
-#[derive(Debug, Parser)]
+#[derive(Clone, Debug, Parser)]
 #[command()]
-pub(crate) struct Arguments {
+pub struct Arguments {
     /// The number of seconds to wait before a connection times out.
     #[arg(long, default_value_t = DEFAULT_CONNECTION_TIMEOUT_SECONDS)]
     pub(crate) connection_timeout: u64,
 
+    /// The number of seconds to wait for a TLS handshake, after the
+    /// connection is established, before it times out.
+    #[arg(long, default_value_t = DEFAULT_TLS_TIMEOUT_SECONDS)]
+    pub(crate) tls_timeout: u64,
+
     /// The number of seconds to wait before a download times out.
     #[arg(long, default_value_t = DEFAULT_DOWNLOAD_TIMEOUT_SECONDS)]
     pub(crate) download_timeout: u64,
 
+    /// The number of times to retry retrieving the mirror data after a
+    /// timeout, connection error or server error, waiting exponentially
+    /// longer between attempts.
+    #[arg(long, value_name = "n", default_value_t = DEFAULT_RETRIES)]
+    pub(crate) retries: u32,
+
+    /// The number of seconds the whole run may take, including retrieving
+    /// and rating the mirrors and writing the output.
+    ///
+    /// When exceeded, the run fails without writing anything.
+    #[arg(long, value_name = "secs")]
+    pub(crate) timeout_total: Option<u64>,
+
+    /// Send all requests through the given proxy, e.g.
+    /// "http://proxy.example:3128".
+    ///
+    /// Without this option, the proxies given by the "HTTP_PROXY",
+    /// "HTTPS_PROXY" and "ALL_PROXY" environment variables are used.
+    #[arg(long, value_name = "URL")]
+    pub(crate) proxy: Option<Url>,
+
+    /// Log more details of each step to standard error. Repeat to increase
+    /// the verbosity further.
+    ///
+    /// By default, only warnings and errors are logged.
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub(crate) verbose: u8,
+
+    /// Only log errors, not warnings, and do not report the rating progress.
+    #[arg(short, long)]
+    pub(crate) quiet: bool,
+
     /// Display a table of the distribution of servers by country.
+    ///
+    /// With "--output-format json", print a JSON array of objects with the
+    /// fields "country", "country_code", "count" and "iso_count" instead.
     #[arg(long)]
     pub(crate) list_countries: bool,
 
+    /// Also display a histogram of the mirrors' MirrorStatus scores.
+    #[arg(long, requires = "list_countries")]
+    pub(crate) histogram: bool,
+
     /// The cache timeout in seconds for the data retrieved from the Arch Linux Mirror Status API.
     #[arg(long, default_value_t = DEFAULT_CACHE_TIMEOUT_SECONDS)]
     pub(crate) cache_timeout: u64,
 
+    /// Always try to fetch fresh mirror data, falling back to the cache if
+    /// the fetch fails.
+    #[arg(long)]
+    pub(crate) refresh: bool,
+
+    /// Store the cache in the given directory instead of the user cache
+    /// directory.
+    #[arg(long, value_name = "path")]
+    pub(crate) cache_dir: Option<PathBuf>,
+
+    /// Always fetch fresh mirror data, ignoring the cache however recent it
+    /// is, and overwrite the cache with it.
+    #[arg(long)]
+    pub(crate) no_cache: bool,
+
+    /// Read the cache if it exists, but never write to it.
+    ///
+    /// Useful when the cache directory is read-only.
+    #[arg(long)]
+    pub(crate) cache_readonly: bool,
+
+    /// Update an existing cache with only the mirrors changed since it was
+    /// retrieved, by passing a "since" query parameter to the URL.
+    ///
+    /// Responses are only merged into the cache if marked by an
+    /// "X-Mirror-Status-Since" header; any other response replaces it. Falls
+    /// back to retrieving all mirrors if the request fails.
+    #[arg(long)]
+    pub(crate) incremental: bool,
+
+    /// Print the path of the cache file for "--url" and whether it is fresh,
+    /// then exit without retrieving the mirror status.
+    #[arg(long)]
+    pub(crate) print_cache_path: bool,
+
     /// The URL from which to retrieve the mirror data in JSON format.
     ///
-    /// If different from the default, it must follow the same format.
+    /// If different from the default, it must follow the same format. A
+    /// "file://" URL is read from disk without caching.
     #[arg(long, default_value_t = DEFAULT_URL.deref().clone())]
     pub(crate) url: Url,
 
@@ -41,10 +123,92 @@ pub(crate) struct Arguments {
     #[arg(long)]
     pub(crate) save: Option<PathBuf>,
 
+    /// Append the selected mirrors to the mirrorlist given to "--save", below
+    /// a dated comment, instead of replacing it.
+    ///
+    /// Mirrors already in the file are skipped. The appended lines are always
+    /// in the pacman format.
+    #[arg(long, requires = "save")]
+    pub(crate) append: bool,
+
+    /// Re-read the mirrorlist once saved by "--save" and check that it lists
+    /// the same mirrors, restoring the previous file if it does not.
+    #[arg(long, requires = "save")]
+    pub(crate) verify_save: bool,
+
+    /// Save the mirrorlist to the given path like "--save", but keep the lines
+    /// of the existing file outside the section managed by reflector-rs, such
+    /// as hand-added mirrors, and place the generated list after them.
+    ///
+    /// The managed section is always in the pacman format.
+    #[arg(long, value_name = "PATH", conflicts_with = "save")]
+    pub(crate) merge_existing: Option<PathBuf>,
+
+    /// The format of the output.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Pacman)]
+    pub(crate) output_format: OutputFormat,
+
+    /// The path of the repositories relative to each mirror URL, as written
+    /// to the mirrorlist.
+    #[arg(long, value_name = "path", default_value = "$repo/os/$arch")]
+    pub(crate) mirror_path: String,
+
+    /// Wrap the comment header of the mirrorlist at the given width.
+    #[arg(long, value_name = "n", default_value_t = 80)]
+    pub(crate) header_width: usize,
+
     /// Sort the mirrorlist.
+    ///
+    /// Sorting by rate downloads a file from every mirror left after the
+    /// other filters to measure its rate, even without "--fastest".
     #[arg(long)]
     pub(crate) sort: Option<SortType>,
 
+    /// How sorting by country orders the countries.
+    ///
+    /// Ordering them by rate puts the fastest countries first, whatever
+    /// their order in "--country", sorts the mirrors of each country by
+    /// rate, and rates the mirrors as sorting by rate does.
+    #[arg(long, value_enum, value_name = "order", default_value_t = CountryOrder::Name)]
+    pub(crate) country_order_by: CountryOrder,
+
+    /// Break ties when sorting by preferring protocols in the given order.
+    ///
+    /// Mirrors using protocols not listed here come last.
+    #[arg(
+        long,
+        value_name = "protocols",
+        value_delimiter = ',',
+        default_value = "https,http,rsync,ftp"
+    )]
+    pub(crate) protocol_sort_weights: Vec<Protocol>,
+
+    /// Put mirrors that support IPv6 ahead of IPv4-only ones, without
+    /// dropping the latter like "--ipv6" does.
+    ///
+    /// This takes precedence over "--sort" and "--sort-expr", which still
+    /// order the mirrors within each group.
+    #[arg(long)]
+    pub(crate) prefer_ipv6: bool,
+
+    /// Collapse mirrors with the same host and path but different protocols
+    /// into one, keeping the protocol preferred by "--protocol-sort-weights".
+    #[arg(long)]
+    pub(crate) dedup: bool,
+
+    /// Sort the mirrorlist by several keys, e.g. "score,-delay".
+    ///
+    /// Each key breaks ties left by the keys before it. Prefix a key with "-"
+    /// to reverse its direction.
+    #[arg(
+        long,
+        value_name = "keys",
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        conflicts_with = "sort"
+    )]
+    pub(crate) sort_expr: Option<Vec<SortKey>>,
+
     /// Number of threads used for rating mirrors.
     ///
     /// This option will speed up the
@@ -53,10 +217,65 @@ pub(crate) struct Arguments {
     /// takes too long without this option then you should probably apply
     /// more filters to reduce the number of rated servers before using this
     /// option.
+    ///
+    /// Mirrors are rated one at a time by default.
     #[arg(long)]
-    // Refer to Rust thread pool
     pub(crate) threads: Option<u64>,
 
+    /// Check that each mirror resolves and accepts a TCP connection before
+    /// including it, over IPv4 with "--ipv4" and over IPv6 with "--ipv6".
+    ///
+    /// Mirrors are checked concurrently according to "--threads".
+    #[arg(long)]
+    pub(crate) verify_connectivity: bool,
+
+    /// The repository whose database is downloaded to rate a mirror, e.g.
+    /// "extra" for "extra/os/x86_64/extra.db".
+    #[arg(long, value_name = "name", default_value = "core", value_parser = parse_repo)]
+    pub(crate) rate_repo: String,
+
+    /// Stop downloading each file used to rate a mirror after n bytes, and
+    /// compute its rate from what was transferred.
+    ///
+    /// The size of the file is first requested once with HEAD: only a range
+    /// of n bytes of a larger file is downloaded, and a smaller one in full.
+    #[arg(long, value_name = "n")]
+    pub(crate) rate_probe_bytes: Option<u64>,
+
+    /// Download the file used to rate a mirror n times, and rate the mirror
+    /// by the median rate.
+    #[arg(long, value_name = "n", default_value_t = 1)]
+    pub(crate) rate_samples: usize,
+
+    /// Blend each measured rate with the rates of earlier runs as an
+    /// exponential moving average, weighing the new rate by alpha, between 0
+    /// and 1.
+    ///
+    /// The blended rates are kept in the cache directory for the next run,
+    /// unless "--cache-readonly" is given; "--no-cache" ignores earlier runs.
+    #[arg(long, value_name = "alpha", value_parser = parse_alpha)]
+    pub(crate) rate_ema: Option<f64>,
+
+    /// Drop the mirrors whose TLS certificate fails validation while rating
+    /// them, e.g. because it expired or names another host, instead of
+    /// leaving them unrated.
+    #[arg(long)]
+    pub(crate) require_valid_tls: bool,
+
+    /// Allow "--fastest" without any option narrowing the mirrors to rate.
+    #[arg(long)]
+    pub(crate) force: bool,
+
+    /// Fail instead of warning if the mirror status has a schema version
+    /// other than the supported one.
+    #[arg(long)]
+    pub(crate) strict: bool,
+
+    /// Write an empty mirrorlist instead of failing when no mirror meets the
+    /// criteria.
+    #[arg(long)]
+    pub(crate) allow_empty: bool,
+
     #[command(flatten, next_help_heading = "Filters")]
     pub(crate) filters: Filters,
 }
@@ -65,13 +284,18 @@ pub(crate) struct Arguments {
 /// contain mirrors for which all of the given conditions are met.
 #[derive(Clone, Debug, Parser)]
 #[group(multiple = true)]
-pub(crate) struct Filters {
+pub struct Filters {
     /// Only return mirrors that have synchronized in the last n hours.
     ///
     /// n may be a float.
     #[arg(short, long, value_name = "n")]
     pub(crate) age: Option<f64>,
 
+    /// Only return mirrors that synchronized within the cutoff reported by
+    /// the Mirror Status API.
+    #[arg(long)]
+    pub(crate) respect_cutoff: bool,
+
     /// Only return mirrors with a reported sync delay of n hours or
     /// less, where n is a float.
     ///
@@ -80,6 +304,13 @@ pub(crate) struct Filters {
     #[arg(long, value_name = "n")]
     pub(crate) delay: Option<f64>,
 
+    /// Only return mirrors whose reported sync delay is within the p-th
+    /// percentile of the delays among the other candidates.
+    ///
+    /// Unlike "--delay", this adapts to the current state of the mirrors.
+    #[arg(long, value_name = "p")]
+    pub(crate) delay_percentile: Option<f64>,
+
     /// Restrict mirrors to selected countries.
     ///
     /// Countries may be given by name or country code, or a mix of both.
@@ -104,14 +335,52 @@ pub(crate) struct Filters {
     /// all be from the United States. When the glob pattern is present, it
     /// only ensures that if certain countries are included in the results,
     /// they will be sorted in the requested order.
-    #[arg(short, long)]
+    #[arg(short, long, value_delimiter = ',')]
     pub(crate) country: Option<Vec<String>>,
 
+    /// Drop mirrors from the given countries, by name or country code.
+    ///
+    /// Multiple countries may be given using commas or by passing this
+    /// option multiple times. This applies after "--country".
+    #[arg(long, value_name = "countries", value_delimiter = ',')]
+    pub(crate) country_exclude: Option<Vec<String>>,
+
+    /// Restrict mirrors to the country of the system locale, as given by
+    /// "LC_ALL" or "LANG", e.g. "US" for "en_US.UTF-8".
+    ///
+    /// An explicit "--country" takes precedence. If the locale names no
+    /// country, no country is selected.
+    #[arg(long, alias = "country-from-locale")]
+    pub(crate) countries_from_locale: bool,
+
+    /// Also include the neighbors of the countries given by code to
+    /// "--country", ordered after the given countries.
+    #[arg(long, requires = "country")]
+    pub(crate) country_expand_neighbors: bool,
+
+    /// Treat a country name or code as an alias of the given country code,
+    /// e.g. "uk=GB".
+    ///
+    /// Common aliases such as "uk" are recognized without this option; aliases
+    /// given here take precedence.
+    #[arg(long, value_name = "alias=code")]
+    pub(crate) country_alias: Option<Vec<CountryAlias>>,
+
     /// Return the n fastest mirrors that meet the other criteria.
-    /// Do not use this option without other filtering options.
+    /// Do not use this option without other filtering options; doing so
+    /// requires "--force".
+    ///
+    /// Only the mirrors kept by "--max-per-asn", "--score" and "--latest"
+    /// are rated, and "--number" then caps the fastest.
     #[arg(short, long, value_name = "n")]
     pub(crate) fastest: Option<usize>,
 
+    /// Guarantee that at least k of the mirrors returned by "--fastest" are
+    /// served over https, replacing the slowest others by the fastest https
+    /// mirrors if needed.
+    #[arg(long, value_name = "k", requires = "fastest")]
+    pub(crate) fastest_min_https: Option<usize>,
+
     /// Include servers that match <regex>, where <regex> is a regular expression.
     #[arg(short, long, value_name = "regex")]
     pub(crate) include: Option<String>,
@@ -120,23 +389,47 @@ pub(crate) struct Filters {
     #[arg(short = 'x', long, value_name = "regex")]
     pub(crate) exclude: Option<String>,
 
+    /// The field of the mirrors that "--include" and "--exclude" match.
+    #[arg(long, value_enum, default_value_t = MatchField::Url)]
+    pub(crate) match_field: MatchField,
+
     /// Limit the list to the n most recently synchronized servers.
+    ///
+    /// Applies to the mirrors kept by "--max-per-asn" and "--score", before
+    /// "--fastest" and "--number".
     #[arg(short, long, value_name = "n")]
     pub(crate) latest: Option<usize>,
 
     /// Limit the list to the n servers with the highest score.
+    ///
+    /// The MirrorStatus score estimates how far behind a mirror is, so the
+    /// highest-scoring mirrors are those with the lowest score value.
+    ///
+    /// Applies to the mirrors kept by "--max-per-asn", before "--latest",
+    /// "--fastest" and "--number".
     #[arg(long, value_name = "n")]
     pub(crate) score: Option<usize>,
 
     /// Return at most n mirrors.
+    ///
+    /// Applies last, after "--max-per-asn", "--score", "--latest",
+    /// "--fastest" and sorting.
     #[arg(short, long, value_name = "n")]
     pub(crate) number: Option<usize>,
 
+    /// Return at most k mirrors from the same hosting provider.
+    ///
+    /// The mirror data does not include ASNs, so mirrors are grouped by the
+    /// last two labels of their domain name instead.
+    #[arg(long, value_name = "k")]
+    pub(crate) max_per_asn: Option<usize>,
+
     /// Match one of the given protocols.
     ///
     /// Multiple protocols may be selected using commas (e.g. "https,http")
-    /// or by passing this option multiple times.
-    #[arg(short, long)]
+    /// or by passing this option multiple times. Without this option, ftp
+    /// mirrors are dropped, as pacman no longer supports them.
+    #[arg(short, long, value_delimiter = ',')]
     pub(crate) protocols: Option<Vec<Protocol>>,
 
     /// Set the minimum completion percent for the returned mirrors.
@@ -145,6 +438,14 @@ pub(crate) struct Filters {
     #[arg(long, default_value_t = 100.0)]
     pub(crate) completion_percent: f64,
 
+    /// The scale of the completion reported by the mirror data.
+    ///
+    /// The Arch Linux Mirror Status API reports a fraction, but custom feeds
+    /// may report a percentage. With "auto", the data is assumed to be a
+    /// fraction if no mirror reports a completion above 1.
+    #[arg(long, value_enum, default_value_t = CompletionScale::Auto)]
+    pub(crate) completion_scale: CompletionScale,
+
     /// Only return mirrors that host ISOs.
     #[arg(long)]
     pub(crate) isos: bool,
@@ -158,29 +459,222 @@ pub(crate) struct Filters {
     pub(crate) ipv6: bool,
 }
 
-#[derive(Clone, Debug, ValueEnum)]
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum)]
 #[value()]
-pub(crate) enum SortType {
+pub enum SortType {
     /// last server synchronization
     Age,
-    /// download rate
+    /// download rate, measured for each mirror remaining after filtering
     Rate,
     /// country name, either alphabetically or in the order given by the --country option
     Country,
     /// MirrorStatus score
     Score,
-    /// MirrorStatus delay
+    /// MirrorStatus delay, mirrors without a known delay last
     Delay,
+    /// MirrorStatus score, completion and variance of the check durations
+    Reliability,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value()]
+pub enum CountryOrder {
+    /// alphabetically, or in the order given by the --country option
+    Name,
+    /// by the rate of their fastest mirror
+    Rate,
+    /// by the average rate of their mirrors
+    AverageRate,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value()]
+pub(crate) enum CompletionScale {
+    /// detect the scale from the reported values
+    Auto,
+    /// completion is reported between 0 and 1
+    Fraction,
+    /// completion is reported between 0 and 100
+    Percent,
+}
+
+/// An alternative name for a country code, as given to `--country-alias`.
+#[derive(Clone, Debug)]
+pub(crate) struct CountryAlias {
+    pub(crate) alias: String,
+    pub(crate) code: String,
+}
+
+/// Parse the weight of the new rate in "--rate-ema", in (0, 1].
+fn parse_alpha(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => Ok(alpha),
+        Ok(_) => Err(format!("{s} is not in (0, 1]")),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Parse a repository name, which is only valid as a single path segment.
+fn parse_repo(s: &str) -> Result<String, String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if s.is_empty() || s.starts_with('.') || !s.chars().all(valid) {
+        return Err(format!("invalid repository name {s:?}"));
+    }
+    Ok(s.to_string())
 }
 
+impl FromStr for CountryAlias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((alias, code)) if !alias.trim().is_empty() && !code.trim().is_empty() => {
+                Ok(CountryAlias {
+                    alias: alias.trim().to_string(),
+                    code: code.trim().to_string(),
+                })
+            }
+            _ => Err(format!("expected <alias>=<code>, got {s:?}")),
+        }
+    }
+}
+
+/// A [`SortType`] with a direction, as given to `--sort-expr`.
+#[derive(Clone, Debug)]
+pub(crate) struct SortKey {
+    pub(crate) sort: SortType,
+    pub(crate) descending: bool,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (descending, name) = match s.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, s),
+        };
+        let sort = <SortType as ValueEnum>::from_str(name.trim(), true)?;
+        Ok(SortKey { sort, descending })
+    }
+}
+
+impl Arguments {
+    /// Whether the mirrors must be rated, for "--fastest" or sorting by rate
+    /// with "--sort" or "--sort-expr", including sorting by country with
+    /// "--country-order-by" a rate.
+    pub(crate) fn needs_rating(&self) -> bool {
+        let sorts_by = |sort: SortType| {
+            self.sort.as_ref() == Some(&sort)
+                || self.sort_expr.iter().flatten().any(|key| key.sort == sort)
+        };
+        let orders_by_rate = self.country_order_by != CountryOrder::Name;
+        self.filters.fastest.is_some()
+            || sorts_by(SortType::Rate)
+            || (orders_by_rate && sorts_by(SortType::Country))
+    }
+}
+
+impl Filters {
+    /// Whether any filter significantly narrows down the mirrors, as
+    /// "--fastest" requires.
+    ///
+    /// "--countries-from-locale" only counts once resolved into a country,
+    /// as a locale naming no country leaves every mirror.
+    pub(crate) fn narrowed(&self) -> bool {
+        self.country.is_some()
+            || self.protocols.is_some()
+            || self.latest.is_some()
+            || self.score.is_some()
+            || self.number.is_some()
+    }
+
+    /// The options of the filters that may drop mirrors, as given on the
+    /// command line, with their values if they take one.
+    pub(crate) fn active(&self) -> Vec<(&'static str, Option<String>)> {
+        let join = |values: &[String]| values.join(",");
+        [
+            ("--age", self.age.map(|age| age.to_string())),
+            ("--respect-cutoff", self.respect_cutoff.then(String::new)),
+            ("--delay", self.delay.map(|delay| delay.to_string())),
+            (
+                "--delay-percentile",
+                self.delay_percentile
+                    .map(|percentile| percentile.to_string()),
+            ),
+            ("--country", self.country.as_deref().map(join)),
+            (
+                "--country-exclude",
+                self.country_exclude.as_deref().map(join),
+            ),
+            ("--include", self.include.clone()),
+            ("--exclude", self.exclude.clone()),
+            (
+                "--protocols",
+                self.protocols.as_ref().map(|protocols| {
+                    let protocols: Vec<_> = protocols.iter().map(|p| p.as_str()).collect();
+                    protocols.join(",")
+                }),
+            ),
+            (
+                "--completion-percent",
+                (self.completion_percent > 0.0).then(|| self.completion_percent.to_string()),
+            ),
+            ("--isos", self.isos.then(String::new)),
+            ("--ipv4", self.ipv4.then(String::new)),
+            ("--ipv6", self.ipv6.then(String::new)),
+        ]
+        .into_iter()
+        .filter_map(|(option, value)| {
+            value.map(|value| (option, Some(value).filter(|value| !value.is_empty())))
+        })
+        .collect()
+    }
+}
+
+/// The field of a mirror matched by "--include" and "--exclude".
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum MatchField {
+    /// the mirror URL
+    Url,
+    /// the country name
+    Country,
+    /// the URL of the mirror's details page
+    Details,
+}
+
+/// The format in which the selected mirrors are output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// A pacman mirrorlist.
+    Pacman,
+    /// A JSON array of the mirrors, in the format of the Mirror Status API.
+    Json,
+    /// One mirror URL per line.
+    Raw,
+}
+
+/// A protocol a mirror is served over.
 #[derive(Clone, Debug, ValueEnum)]
 #[value()]
-pub(crate) enum Protocol {
+pub enum Protocol {
     Ftp,
     Http,
     Https,
     Rsync,
 }
+
+impl Protocol {
+    /// The protocol as named in the mirror data.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Ftp => "ftp",
+            Protocol::Http => "http",
+            Protocol::Https => "https",
+            Protocol::Rsync => "rsync",
+        }
+    }
+}
 mod tests {
 
     #[test]
@@ -190,4 +684,147 @@ mod tests {
         use super::Arguments;
         Arguments::command().debug_assert()
     }
+
+    #[test]
+    fn narrowed() {
+        use clap::Parser;
+
+        use super::Arguments;
+        let narrowed = |args: &[&str]| {
+            Arguments::parse_from([&["reflector-rs"], args].concat())
+                .filters
+                .narrowed()
+        };
+        assert!(!narrowed(&["--fastest", "5"]));
+        assert!(!narrowed(&["--fastest", "5", "--force"]));
+        assert!(narrowed(&["--fastest", "5", "--country", "de"]));
+        assert!(narrowed(&["--fastest", "5", "--latest", "20"]));
+        assert!(!narrowed(&["--fastest", "5", "--countries-from-locale"]));
+    }
+
+    #[test]
+    fn rate_repo() {
+        use clap::Parser;
+
+        use super::Arguments;
+        let parse = |args: &[&str]| Arguments::try_parse_from([&["reflector-rs"], args].concat());
+        assert_eq!(parse(&[]).unwrap().rate_repo, "core");
+        assert_eq!(
+            parse(&["--rate-repo", "core-testing"]).unwrap().rate_repo,
+            "core-testing"
+        );
+        for repo in ["", "a/b", "..", "extra?"] {
+            assert!(parse(&["--rate-repo", repo]).is_err(), "{repo:?}");
+        }
+    }
+
+    #[test]
+    fn rate_ema() {
+        use clap::Parser;
+
+        use super::Arguments;
+        let parse = |args: &[&str]| Arguments::try_parse_from([&["reflector-rs"], args].concat());
+        assert_eq!(parse(&["--rate-ema", "0.3"]).unwrap().rate_ema, Some(0.3));
+        assert_eq!(parse(&["--rate-ema", "1"]).unwrap().rate_ema, Some(1.0));
+        for alpha in ["0", "1.5", "-0.1", "fast"] {
+            assert!(parse(&["--rate-ema", alpha]).is_err(), "{alpha}");
+        }
+    }
+
+    #[test]
+    fn needs_rating() {
+        use clap::Parser;
+
+        use super::Arguments;
+        let needs_rating = |args: &[&str]| {
+            Arguments::parse_from([&["reflector-rs"], args].concat()).needs_rating()
+        };
+        assert!(needs_rating(&["--sort", "rate"]));
+        assert!(needs_rating(&["--fastest", "5", "--force"]));
+        assert!(!needs_rating(&["--sort", "score"]));
+        assert!(needs_rating(&["--sort-expr", "score,-rate,delay"]));
+        assert!(!needs_rating(&["--sort-expr", "score,-delay"]));
+        assert!(!needs_rating(&["--sort", "country"]));
+        assert!(needs_rating(&[
+            "--sort",
+            "country",
+            "--country-order-by",
+            "rate"
+        ]));
+        assert!(needs_rating(&[
+            "--sort-expr",
+            "country",
+            "--country-order-by",
+            "average-rate"
+        ]));
+        assert!(!needs_rating(&[
+            "--sort",
+            "score",
+            "--country-order-by",
+            "rate"
+        ]));
+    }
+
+    #[test]
+    fn countries_from_locale() {
+        use clap::Parser;
+
+        use super::Arguments;
+        for flag in ["--countries-from-locale", "--country-from-locale"] {
+            let arguments = Arguments::parse_from(["reflector-rs", flag]);
+            assert!(arguments.filters.countries_from_locale, "{flag}");
+        }
+    }
+
+    #[test]
+    fn split_list_args() {
+        use clap::Parser;
+
+        use super::Arguments;
+        let arguments = Arguments::parse_from([
+            "reflector-rs",
+            "-c",
+            "se,no",
+            "-c",
+            "dk",
+            "-p",
+            "https,http",
+        ]);
+        assert_eq!(arguments.filters.country.unwrap(), ["se", "no", "dk"]);
+        let protocols = arguments.filters.protocols.unwrap();
+        let protocols: Vec<_> = protocols.iter().map(|protocol| protocol.as_str()).collect();
+        assert_eq!(protocols, ["https", "http"]);
+    }
+
+    #[test]
+    fn active_filters() {
+        use clap::Parser;
+
+        use super::Arguments;
+        let active = |args: &[&str]| {
+            Arguments::parse_from([&["reflector-rs"], args].concat())
+                .filters
+                .active()
+        };
+        assert_eq!(
+            active(&[]),
+            [("--completion-percent", Some("100".to_string()))]
+        );
+        assert_eq!(
+            active(&[
+                "--age",
+                "1",
+                "--isos",
+                "-c",
+                "de,fr",
+                "--completion-percent",
+                "0"
+            ]),
+            [
+                ("--age", Some("1".to_string())),
+                ("--country", Some("de,fr".to_string())),
+                ("--isos", None),
+            ]
+        );
+    }
 }
@@ -0,0 +1,129 @@
+//! Verification that mirrors are reachable, for `--verify-connectivity`.
+
+use std::{net::SocketAddr, time::Duration};
+
+use log::warn;
+use tokio::{
+    net::{lookup_host, TcpStream},
+    time::timeout,
+};
+use url::Url;
+
+use crate::{rate::probe_each, Mirror};
+
+/// Port of rsync daemons, which the `url` crate does not know about.
+const RSYNC_PORT: u16 = 873;
+
+/// The IP versions over which a mirror must be reachable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Families {
+    pub(crate) ipv4: bool,
+    pub(crate) ipv6: bool,
+}
+
+impl Families {
+    /// Whether the `connected` addresses cover these families.
+    fn satisfied_by(self, connected: &[SocketAddr]) -> bool {
+        let (ipv4, ipv6) = (
+            connected.iter().any(SocketAddr::is_ipv4),
+            connected.iter().any(SocketAddr::is_ipv6),
+        );
+        if self.ipv4 || self.ipv6 {
+            (!self.ipv4 || ipv4) && (!self.ipv6 || ipv6)
+        } else {
+            ipv4 || ipv6
+        }
+    }
+}
+
+/// Whether the host of `url` resolves and accepts TCP connections within
+/// `connect_timeout` on each of the required `families`.
+///
+/// Only the first address of each family is tried.
+pub(crate) async fn reachable(url: &Url, families: Families, connect_timeout: Duration) -> bool {
+    let (Some(host), Some(port)) = (
+        url.host_str(),
+        url.port_or_known_default()
+            .or((url.scheme() == "rsync").then_some(RSYNC_PORT)),
+    ) else {
+        return false;
+    };
+    // IPv6 literals keep their brackets in the host string.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let Ok(Ok(addresses)) = timeout(connect_timeout, lookup_host((host, port))).await else {
+        return false;
+    };
+    let addresses: Vec<_> = addresses.collect();
+    let first = |ipv6: bool| addresses.iter().find(|address| address.is_ipv6() == ipv6);
+    let mut connected = Vec::new();
+    for address in [first(false), first(true)].into_iter().flatten() {
+        if let Ok(Ok(_)) = timeout(connect_timeout, TcpStream::connect(address)).await {
+            connected.push(*address);
+        }
+    }
+    families.satisfied_by(&connected)
+}
+
+/// Keep only the mirrors that are reachable on the required `families`,
+/// checking at most `threads` of them at a time.
+pub(crate) async fn verify_connectivity(
+    mirrors: Vec<Mirror>,
+    families: Families,
+    connect_timeout: Duration,
+    threads: usize,
+) -> Vec<Mirror> {
+    let reachable = probe_each(
+        &mirrors,
+        threads,
+        |mirror| async move { reachable(&mirror.url, families, connect_timeout).await },
+        |_| (),
+    )
+    .await;
+    mirrors
+        .into_iter()
+        .zip(reachable)
+        .filter_map(|(mirror, reachable)| {
+            if reachable != Some(true) {
+                warn!("dropping unreachable mirror {}", mirror.url);
+                return None;
+            }
+            Some(mirror)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{mirror, serve};
+
+    #[tokio::test]
+    async fn test_verify_connectivity() {
+        let open = serve(vec![Vec::new(), Vec::new()]).await;
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap()
+        };
+        let mirrors: Vec<_> = [closed, open.clone()]
+            .into_iter()
+            .map(|url| Mirror { url, ..mirror() })
+            .collect();
+        let verified = |families| {
+            let mirrors = mirrors.clone();
+            async move {
+                verify_connectivity(mirrors, families, Duration::from_secs(1), 2)
+                    .await
+                    .into_iter()
+                    .map(|mirror| mirror.url)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(verified(Families::default()).await, [open]);
+        let ipv6 = Families {
+            ipv4: false,
+            ipv6: true,
+        };
+        // The local server only listens on IPv4.
+        assert!(verified(ipv6).await.is_empty());
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::{cli::CountryAlias, Mirror};
+
+/// A country given either by ISO 3166-1 alpha-2 code or by name.
+///
+/// Codes are normalized to uppercase and names to lowercase with single
+/// spaces and without accents, so that spellings differing only in case,
+/// spacing or diacritics are equal.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct Country(String);
+
+impl Country {
+    pub(crate) fn new(country: &str) -> Self {
+        let country = country.trim();
+        if country.len() == 2 && country.bytes().all(|b| b.is_ascii_alphabetic()) {
+            Country(country.to_ascii_uppercase())
+        } else {
+            Country(
+                country
+                    .split_whitespace()
+                    .map(|word| word.to_lowercase().chars().map(fold_accent).collect())
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            )
+        }
+    }
+}
+
+impl fmt::Display for Country {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Lowercase Latin letters with diacritics, grouped by the ASCII letter
+/// they are folded to.
+const ACCENTS: &[(&str, char)] = &[
+    ("àáâãäåāăą", 'a'),
+    ("çćĉċč", 'c'),
+    ("ďđ", 'd'),
+    ("èéêëēĕėęě", 'e'),
+    ("ĝğġģ", 'g'),
+    ("ìíîïĩīĭįı", 'i'),
+    ("ķ", 'k'),
+    ("ĺļľŀł", 'l'),
+    ("ñńņňŉ", 'n'),
+    ("òóôõöøōŏő", 'o'),
+    ("ŕŗř", 'r'),
+    ("śŝşšș", 's'),
+    ("ţťŧț", 't'),
+    ("ùúûüũūŭůűų", 'u'),
+    ("ýÿ", 'y'),
+    ("źżž", 'z'),
+];
+
+/// Fold a lowercase letter with a diacritic to its ASCII base letter,
+/// leaving other characters unchanged.
+fn fold_accent(c: char) -> char {
+    ACCENTS
+        .iter()
+        .find(|(accented, _)| accented.contains(c))
+        .map_or(c, |&(_, base)| base)
+}
+
+/// Informal names and codes commonly used for countries, mapped to the ISO
+/// 3166-1 alpha-2 codes used by the Mirror Status API.
+const ALIASES: &[(&str, &str)] = &[
+    ("UK", "GB"),
+    ("England", "GB"),
+    ("Britain", "GB"),
+    ("Great Britain", "GB"),
+    ("EL", "GR"),
+    ("USA", "US"),
+    ("America", "US"),
+    ("Holland", "NL"),
+    ("Turkey", "TR"),
+    ("Czech Republic", "CZ"),
+    ("Ivory Coast", "CI"),
+    ("South Korea", "KR"),
+    ("Korea", "KR"),
+    ("Russia", "RU"),
+    ("Vietnam", "VN"),
+];
+
+/// Resolve `token` through the country aliases, preferring user-provided
+/// `overrides` to the built-in table.
+///
+/// Tokens that are not aliases are returned unchanged.
+pub(crate) fn resolve_alias<'a>(token: &'a str, overrides: &'a [CountryAlias]) -> &'a str {
+    overrides
+        .iter()
+        .map(|alias| (alias.alias.as_str(), alias.code.as_str()))
+        .chain(ALIASES.iter().copied())
+        .find(|(alias, _)| Country::new(alias) == Country::new(token))
+        .map_or(token, |(_, code)| code)
+}
+
+/// Whether `mirror` is in the country given by name or code in `token`,
+/// ignoring case and accents and resolving aliases.
+pub(crate) fn matches_country(mirror: &Mirror, token: &str, overrides: &[CountryAlias]) -> bool {
+    if token.eq_ignore_ascii_case(&mirror.country_code) {
+        return true;
+    }
+    let token = Country::new(resolve_alias(token, overrides));
+    token == Country::new(&mirror.country) || token == Country::new(&mirror.country_code)
+}
+
+/// Land neighbors of countries commonly hosting Arch Linux mirrors, keyed by
+/// ISO 3166-1 alpha-2 code.
+///
+/// This is deliberately minimal; countries missing here are simply not
+/// expanded.
+const NEIGHBORS: &[(&str, &[&str])] = &[
+    ("AT", &["DE", "CZ", "SK", "HU", "SI", "IT", "CH", "LI"]),
+    ("BE", &["NL", "DE", "LU", "FR"]),
+    ("BG", &["RO", "RS", "MK", "GR", "TR"]),
+    ("CA", &["US"]),
+    ("CH", &["DE", "AT", "LI", "IT", "FR"]),
+    (
+        "CN",
+        &["MN", "RU", "KZ", "KP", "VN", "LA", "MM", "IN", "NP", "BT"],
+    ),
+    ("CZ", &["DE", "PL", "SK", "AT"]),
+    (
+        "DE",
+        &["DK", "PL", "CZ", "AT", "CH", "FR", "LU", "BE", "NL"],
+    ),
+    ("DK", &["DE"]),
+    ("ES", &["PT", "FR", "AD"]),
+    ("FI", &["SE", "NO", "RU"]),
+    ("FR", &["BE", "LU", "DE", "CH", "IT", "MC", "ES", "AD"]),
+    ("HU", &["AT", "SK", "UA", "RO", "RS", "HR", "SI"]),
+    ("IT", &["FR", "CH", "AT", "SI", "SM", "VA"]),
+    ("LU", &["BE", "DE", "FR"]),
+    ("NL", &["DE", "BE"]),
+    ("NO", &["SE", "FI", "RU"]),
+    ("PL", &["DE", "CZ", "SK", "UA", "BY", "LT", "RU"]),
+    ("PT", &["ES"]),
+    ("RO", &["UA", "MD", "BG", "RS", "HU"]),
+    (
+        "RU",
+        &[
+            "NO", "FI", "EE", "LV", "LT", "PL", "BY", "UA", "GE", "AZ", "KZ", "CN", "MN", "KP",
+        ],
+    ),
+    ("SE", &["NO", "FI"]),
+    ("SK", &["CZ", "PL", "UA", "HU", "AT"]),
+    ("UA", &["PL", "SK", "HU", "RO", "MD", "BY", "RU"]),
+    ("US", &["CA", "MX"]),
+];
+
+/// The country code of a POSIX `locale` such as `en_US.UTF-8` or
+/// `de_DE@euro`, if it names a country.
+pub(crate) fn locale_country(locale: &str) -> Option<String> {
+    let locale = locale.split(['.', '@']).next()?;
+    let (_, territory) = locale.split_once('_')?;
+    (territory.len() == 2 && territory.bytes().all(|b| b.is_ascii_alphabetic()))
+        .then(|| territory.to_ascii_uppercase())
+}
+
+/// The country code of the system locale, from the first of `LC_ALL` and
+/// `LANG` that is set and not empty.
+///
+/// Warns if neither is set or the locale names no country.
+pub(crate) fn system_locale_country() -> Option<String> {
+    let Some(locale) = ["LC_ALL", "LANG"]
+        .into_iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|locale| !locale.is_empty())
+    else {
+        warn!("cannot infer a country from the locale, as neither LC_ALL nor LANG is set");
+        return None;
+    };
+    let country = locale_country(&locale);
+    if country.is_none() {
+        warn!("cannot infer a country from the locale {locale:?}");
+    }
+    country
+}
+
+/// Append the neighbors of each requested country code after the requested
+/// countries, in table order, skipping countries that are already present.
+///
+/// Tokens that are not known country codes, such as names or `*`, are kept
+/// but not expanded.
+pub(crate) fn expand_neighbors(countries: Vec<String>) -> Vec<String> {
+    let neighbors: Vec<&str> = countries
+        .iter()
+        .filter_map(|country| {
+            NEIGHBORS
+                .iter()
+                .find(|(code, _)| Country::new(code) == Country::new(country))
+        })
+        .flat_map(|(_, neighbors)| neighbors.iter().copied())
+        .collect();
+    let mut expanded = countries;
+    for neighbor in neighbors {
+        if !expanded
+            .iter()
+            .any(|country| Country::new(country) == Country::new(neighbor))
+        {
+            expanded.push(neighbor.to_string());
+        }
+    }
+    expanded
+}
+
+/// Number of mirrors in a country, as listed by `--list-countries`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct CountryCount<'a> {
+    #[serde(rename = "country")]
+    pub(crate) name: &'a str,
+    #[serde(rename = "country_code")]
+    pub(crate) code: &'a str,
+    /// Number of mirrors in the country.
+    #[serde(rename = "count")]
+    pub(crate) mirrors: usize,
+    /// Number of those mirrors that host ISOs.
+    #[serde(rename = "iso_count")]
+    pub(crate) isos: usize,
+}
+
+/// Count the mirrors of each country, sorted by country name.
+///
+/// Countries are grouped by [`Country`], as when filtering, so spellings
+/// differing only in case, spacing or accents are counted together under
+/// the first spelling found.
+pub(crate) fn count_by_country(mirrors: &[Mirror]) -> Vec<CountryCount<'_>> {
+    let mut counts = BTreeMap::new();
+    for mirror in mirrors {
+        let count = counts
+            .entry((
+                Country::new(&mirror.country),
+                Country::new(&mirror.country_code),
+            ))
+            .or_insert(CountryCount {
+                name: &mirror.country,
+                code: &mirror.country_code,
+                mirrors: 0,
+                isos: 0,
+            });
+        count.mirrors += 1;
+        count.isos += usize::from(mirror.isos);
+    }
+    counts.into_values().collect()
+}
+
+/// Render the number of mirrors of each country as an aligned table.
+pub(crate) fn render_country_table(mirrors: &[Mirror]) -> String {
+    let counts = count_by_country(mirrors);
+    let headers = ["Country", "Code", "Mirrors", "ISOs"];
+    let name_width = counts
+        .iter()
+        .map(|count| count.name.chars().count())
+        .chain([headers[0].len()])
+        .max()
+        .unwrap_or_default();
+    let mut out = String::new();
+    let mut row = |cells: [&str; 4]| {
+        writeln!(
+            out,
+            "{:<name_width$}  {:<4}  {:>7}  {:>4}",
+            cells[0], cells[1], cells[2], cells[3]
+        )
+        .unwrap();
+    };
+    row(headers);
+    for count in &counts {
+        row([
+            count.name,
+            count.code,
+            &count.mirrors.to_string(),
+            &count.isos.to_string(),
+        ]);
+    }
+    out
+}
+
+/// Render the number of mirrors of each country as a JSON array, sorted by
+/// country name like [`render_country_table`].
+pub(crate) fn render_country_json(mirrors: &[Mirror]) -> String {
+    let mut out = serde_json::to_string_pretty(&count_by_country(mirrors))
+        .expect("country counts are always serializable");
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::mirror;
+
+    #[test]
+    fn test_country_normalization() {
+        assert_eq!(Country::new("de").to_string(), "DE");
+        assert_eq!(
+            Country::new(" United  Kingdom ").to_string(),
+            "united kingdom"
+        );
+        assert_eq!(Country::new("TÜRKIYE"), Country::new("Türkiye"));
+        assert_eq!(Country::new("Türkiye").to_string(), "turkiye");
+        assert_eq!(Country::new("Réunion"), Country::new("reunion"));
+        assert_eq!(Country::new("Côte d'Ivoire").to_string(), "cote d'ivoire");
+        assert_eq!(Country::new("gb"), Country::new("GB"));
+        assert_ne!(Country::new("GB"), Country::new("United Kingdom"));
+        assert_ne!(Country::new("Germany"), Country::new("France"));
+    }
+
+    #[test]
+    fn test_country_aliases() {
+        let british = Mirror {
+            country: "United Kingdom".to_string(),
+            country_code: "GB".to_string(),
+            ..mirror()
+        };
+        assert!(matches_country(&british, "uk", &[]));
+        assert!(matches_country(&british, "united kingdom", &[]));
+        assert!(!matches_country(&british, "de", &[]));
+
+        let turkish = Mirror {
+            country: "Türkiye".to_string(),
+            country_code: "TR".to_string(),
+            ..mirror()
+        };
+        assert!(matches_country(&turkish, "Turkey", &[]));
+        assert!(matches_country(&turkish, "turkiye", &[]));
+        assert!(matches_country(&turkish, "tr", &[]));
+
+        let overrides = ["uk=DE".parse().unwrap()];
+        assert!(!matches_country(&british, "uk", &overrides));
+        assert!(matches_country(&mirror(), "uk", &overrides));
+    }
+
+    #[test]
+    fn test_locale_country() {
+        assert_eq!(locale_country("en_US.UTF-8").as_deref(), Some("US"));
+        assert_eq!(locale_country("de_DE@euro").as_deref(), Some("DE"));
+        assert_eq!(locale_country("sr_RS.UTF-8@latin").as_deref(), Some("RS"));
+        assert_eq!(locale_country("C.UTF-8"), None);
+        assert_eq!(locale_country("POSIX"), None);
+        assert_eq!(locale_country("eo"), None);
+    }
+
+    #[test]
+    fn test_expand_neighbors() {
+        assert_eq!(
+            expand_neighbors(vec!["de".to_string()]),
+            ["de", "DK", "PL", "CZ", "AT", "CH", "FR", "LU", "BE", "NL"]
+        );
+        assert_eq!(
+            expand_neighbors(vec!["nl".to_string(), "be".to_string()]),
+            ["nl", "be", "DE", "LU", "FR"]
+        );
+        assert_eq!(expand_neighbors(vec!["Atlantis".to_string()]), ["Atlantis"]);
+    }
+
+    #[test]
+    fn test_count_by_country() {
+        let mirrors: Vec<_> = [
+            ("Germany", "DE", true),
+            ("France", "FR", false),
+            ("Germany", "DE", false),
+            ("Germany", "DE", true),
+            ("germany ", "de", false),
+        ]
+        .into_iter()
+        .map(|(country, code, isos)| Mirror {
+            country: country.to_string(),
+            country_code: code.to_string(),
+            isos,
+            ..mirror()
+        })
+        .collect();
+        let counts = count_by_country(&mirrors);
+        assert_eq!(
+            counts,
+            [
+                CountryCount {
+                    name: "France",
+                    code: "FR",
+                    mirrors: 1,
+                    isos: 0,
+                },
+                CountryCount {
+                    name: "Germany",
+                    code: "DE",
+                    mirrors: 4,
+                    isos: 2,
+                },
+            ]
+        );
+        assert_eq!(
+            render_country_table(&mirrors),
+            "\
+Country  Code  Mirrors  ISOs
+France   FR          1     0
+Germany  DE          4     2
+"
+        );
+    }
+
+    #[test]
+    fn test_render_country_json() {
+        let mirrors = [
+            mirror(),
+            Mirror {
+                country: "France".to_string(),
+                country_code: "FR".to_string(),
+                isos: false,
+                ..mirror()
+            },
+        ];
+        let json: serde_json::Value = serde_json::from_str(&render_country_json(&mirrors)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"country": "France", "country_code": "FR", "count": 1, "iso_count": 0},
+                {"country": "Germany", "country_code": "DE", "count": 1, "iso_count": 1},
+            ])
+        );
+    }
+}
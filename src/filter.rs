@@ -0,0 +1,976 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use url::Host;
+
+use crate::{
+    cli::{CompletionScale, Filters, MatchField, Protocol, SortType},
+    country::{expand_neighbors, matches_country},
+    pattern::{Regex, RegexError},
+    sort::{compare_by, compare_urls, protocol_rank},
+    timestamp::parse_rfc3339,
+    Mirror, MirrorStatus,
+};
+
+/// Protocols of the mirrors kept without `--protocols`, leaving out FTP,
+/// which pacman no longer supports.
+pub(crate) const DEFAULT_PROTOCOLS: [Protocol; 3] =
+    [Protocol::Https, Protocol::Http, Protocol::Rsync];
+
+/// Apply the inclusive `filters` to the mirrors of `status`.
+///
+/// Fails if the `--include` or `--exclude` expression is invalid.
+pub(crate) fn apply_filters(
+    mut status: MirrorStatus,
+    filters: &Filters,
+) -> Result<Vec<Mirror>, RegexError> {
+    let include = filters.include.as_deref().map(Regex::new).transpose()?;
+    let exclude = filters.exclude.as_deref().map(Regex::new).transpose()?;
+    let mut mirrors = std::mem::take(&mut status.urls);
+    let countries =
+        country_tokens(filters).filter(|countries| !countries.iter().any(|country| country == "*"));
+    if let Some(countries) = countries {
+        let overrides = filters.country_alias.as_deref().unwrap_or_default();
+        mirrors.retain(|mirror| {
+            countries
+                .iter()
+                .any(|country| matches_country(mirror, country, overrides))
+        });
+    }
+    if let Some(excluded) = &filters.country_exclude {
+        let overrides = filters.country_alias.as_deref().unwrap_or_default();
+        mirrors.retain(|mirror| {
+            !excluded
+                .iter()
+                .map(|country| country.trim())
+                .filter(|country| !country.is_empty())
+                .any(|country| matches_country(mirror, country, overrides))
+        });
+    }
+    if let Some(age) = filters.age {
+        mirrors = filter_by_age(mirrors, age);
+    }
+    if filters.respect_cutoff {
+        mirrors = filter_by_cutoff(mirrors, &status);
+    }
+    if let Some(delay) = filters.delay {
+        mirrors = filter_by_delay(mirrors, delay);
+    }
+    if let Some(percentile) = filters.delay_percentile {
+        mirrors = filter_by_delay_percentile(mirrors, percentile);
+    }
+    let protocols = filters.protocols.as_deref().unwrap_or(&DEFAULT_PROTOCOLS);
+    mirrors.retain(|mirror| matches_protocol(mirror, protocols));
+    mirrors = filter_by_completion(
+        mirrors,
+        filters.completion_percent,
+        filters.completion_scale,
+    );
+    mirrors.retain(|mirror| matches_capabilities(mirror, filters));
+    Ok(filter_by_regex(
+        mirrors,
+        filters.match_field,
+        include.as_ref(),
+        exclude.as_ref(),
+    ))
+}
+
+/// The countries requested by `--country`, followed by their neighbors with `--country-expand-neighbors`.
+///
+/// The tokens may include the glob `*`, which matches every country and so
+/// disables filtering by country, only affecting the sort order.
+pub(crate) fn country_tokens(filters: &Filters) -> Option<Vec<String>> {
+    let countries = filters
+        .country
+        .as_ref()?
+        .iter()
+        .map(|country| country.trim())
+        .filter(|country| !country.is_empty())
+        .map(ToString::to_string)
+        .collect();
+    Some(if filters.country_expand_neighbors {
+        expand_neighbors(countries)
+    } else {
+        countries
+    })
+}
+
+/// Apply the limiting `filters`, which keep a number of the best mirrors by
+/// some criterion, to `mirrors` that already passed the inclusive filters.
+///
+/// The limits apply in a fixed order, each to the mirrors kept by the
+/// previous one: `--max-per-asn`, `--score` then `--latest`. The caller then
+/// rates the rest for `--fastest`, sorts them and caps them with
+/// [`apply_number`].
+pub(crate) fn apply_limits(mut mirrors: Vec<Mirror>, filters: &Filters) -> Vec<Mirror> {
+    if let Some(max) = filters.max_per_asn {
+        mirrors = filter_by_max_per_provider(mirrors, max);
+    }
+    if let Some(score) = filters.score {
+        mirrors = filter_best_score(mirrors, score);
+    }
+    if let Some(latest) = filters.latest {
+        mirrors = filter_latest(mirrors, latest);
+    }
+    mirrors
+}
+
+/// Cap `mirrors` at `--number` entries.
+///
+/// This runs last, after the limits of [`apply_limits`] and sorting, so that
+/// it only ever trims the mirrors those steps selected.
+pub(crate) fn apply_number(mut mirrors: Vec<Mirror>, filters: &Filters) -> Vec<Mirror> {
+    if let Some(number) = filters.number {
+        mirrors.truncate(number);
+    }
+    mirrors
+}
+
+/// Collapse mirrors sharing the same host and path into one, keeping the
+/// one whose protocol comes first in `protocols`.
+///
+/// The path is compared without trailing slashes, and the kept mirrors stay
+/// in their original order.
+pub(crate) fn dedup_mirrors(mirrors: Vec<Mirror>, protocols: &[Protocol]) -> Vec<Mirror> {
+    let key = |mirror: &Mirror| {
+        (
+            mirror
+                .url
+                .host_str()
+                .unwrap_or_default()
+                .to_ascii_lowercase(),
+            mirror.url.path().trim_end_matches('/').to_string(),
+        )
+    };
+    let mut best: HashMap<_, usize> = HashMap::new();
+    for (index, mirror) in mirrors.iter().enumerate() {
+        best.entry(key(mirror))
+            .and_modify(|best| {
+                if protocol_rank(mirror, protocols) < protocol_rank(&mirrors[*best], protocols) {
+                    *best = index;
+                }
+            })
+            .or_insert(index);
+    }
+    mirrors
+        .into_iter()
+        .enumerate()
+        .filter(|(index, mirror)| best[&key(mirror)] == *index)
+        .map(|(_, mirror)| mirror)
+        .collect()
+}
+
+/// Keep the `n` most recently synchronized mirrors, most recent first.
+///
+/// Mirrors without a valid `last_sync` are considered the oldest, and
+/// mirrors synchronized at the same time are kept in URL order.
+pub(crate) fn filter_latest(mut mirrors: Vec<Mirror>, n: usize) -> Vec<Mirror> {
+    mirrors
+        .sort_by(|a, b| compare_by(a, b, &SortType::Age, false).then_with(|| compare_urls(a, b)));
+    mirrors.truncate(n);
+    mirrors
+}
+
+/// Keep the `n` mirrors with the highest download rate, fastest first.
+///
+/// The mirrors must already be rated; unrated mirrors are considered the
+/// slowest. If fewer than `min_https` of the fastest are served over https,
+/// the slowest others are replaced by the fastest rated https mirrors left
+/// out, as far as there are any.
+pub(crate) fn filter_fastest(mut mirrors: Vec<Mirror>, n: usize, min_https: usize) -> Vec<Mirror> {
+    let by_rate = |a: &Mirror, b: &Mirror| compare_by(a, b, &SortType::Rate, false);
+    mirrors.sort_by(by_rate);
+    let n = n.min(mirrors.len());
+    let https = |mirror: &Mirror| mirror.protocol.eq_ignore_ascii_case("https");
+    let missing = min_https
+        .min(n)
+        .saturating_sub(mirrors[..n].iter().filter(|mirror| https(mirror)).count());
+    let pulled = (n..mirrors.len())
+        .filter(|&index| https(&mirrors[index]) && mirrors[index].rate.is_some())
+        .take(missing);
+    let replaced = (0..n).rev().filter(|&index| !https(&mirrors[index]));
+    for (replaced, pulled) in replaced.zip(pulled).collect::<Vec<_>>() {
+        mirrors.swap(replaced, pulled);
+    }
+    mirrors.truncate(n);
+    mirrors.sort_by(by_rate);
+    mirrors
+}
+
+/// Keep the `n` mirrors with the best MirrorStatus score, best first.
+///
+/// The score roughly estimates in hours how far behind the mirror is, so
+/// lower is better. Mirrors without a score are dropped.
+pub(crate) fn filter_best_score(mut mirrors: Vec<Mirror>, n: usize) -> Vec<Mirror> {
+    mirrors.retain(|mirror| mirror.score.is_some());
+    mirrors.sort_by(|a, b| compare_by(a, b, &SortType::Score, false));
+    mirrors.truncate(n);
+    mirrors
+}
+
+/// The `field` of `mirror` matched by `--include` and `--exclude`.
+fn match_field(mirror: &Mirror, field: MatchField) -> &str {
+    match field {
+        MatchField::Url => mirror.url.as_str(),
+        MatchField::Country => &mirror.country,
+        MatchField::Details => &mirror.details,
+    }
+}
+
+/// Keep only mirrors whose `field` matches `include`, then drop those whose
+/// `field` matches `exclude`.
+pub(crate) fn filter_by_regex(
+    mirrors: Vec<Mirror>,
+    field: MatchField,
+    include: Option<&Regex>,
+    exclude: Option<&Regex>,
+) -> Vec<Mirror> {
+    mirrors
+        .into_iter()
+        .filter(|mirror| {
+            let field = match_field(mirror, field);
+            include.is_none_or(|include| include.is_match(field))
+                && !exclude.is_some_and(|exclude| exclude.is_match(field))
+        })
+        .collect()
+}
+
+/// Keep only mirrors that synchronized within `max_age_hours` before `now`.
+///
+/// Mirrors that never synchronized, or whose `last_sync` cannot be parsed,
+/// are dropped.
+pub(crate) fn filter_by_age_at(
+    mirrors: Vec<Mirror>,
+    max_age_hours: f64,
+    now: SystemTime,
+) -> Vec<Mirror> {
+    let max_age = Duration::from_secs_f64(max_age_hours.max(0.0) * 3600.0);
+    mirrors
+        .into_iter()
+        .filter(|mirror| {
+            mirror
+                .last_sync
+                .as_deref()
+                .and_then(parse_rfc3339)
+                .is_some_and(|last_sync| {
+                    now.duration_since(last_sync).unwrap_or_default() <= max_age
+                })
+        })
+        .collect()
+}
+
+/// Keep only mirrors that synchronized within the last `max_age_hours`.
+pub(crate) fn filter_by_age(mirrors: Vec<Mirror>, max_age_hours: f64) -> Vec<Mirror> {
+    filter_by_age_at(mirrors, max_age_hours, SystemTime::now())
+}
+
+/// Whether the protocol of `mirror` is one of `protocols`, ignoring case.
+pub(crate) fn matches_protocol(mirror: &Mirror, protocols: &[Protocol]) -> bool {
+    protocols
+        .iter()
+        .any(|protocol| protocol.as_str().eq_ignore_ascii_case(&mirror.protocol))
+}
+
+/// Whether `mirror` has every capability required by `--isos`, `--ipv4` and
+/// `--ipv6` in `filters`.
+pub(crate) fn matches_capabilities(mirror: &Mirror, filters: &Filters) -> bool {
+    (!filters.isos || mirror.isos)
+        && (!filters.ipv4 || mirror.ipv4)
+        && (!filters.ipv6 || mirror.ipv6)
+}
+
+/// Keep only mirrors with a reported sync delay of at most `max_delay_hours`.
+///
+/// Mirrors without a reported delay are dropped, since they cannot be checked.
+pub(crate) fn filter_by_delay(mirrors: Vec<Mirror>, max_delay_hours: f64) -> Vec<Mirror> {
+    mirrors
+        .into_iter()
+        .filter(|mirror| {
+            mirror
+                .delay
+                .is_some_and(|delay| delay as f64 / 3600.0 <= max_delay_hours)
+        })
+        .collect()
+}
+
+/// Keep only mirrors whose reported `delay` lies within the `percentile`-th
+/// percentile (nearest-rank) of the delays among `mirrors`.
+///
+/// Mirrors without a reported delay are dropped, since they cannot be ranked.
+pub(crate) fn filter_by_delay_percentile(mirrors: Vec<Mirror>, percentile: f64) -> Vec<Mirror> {
+    let mut delays: Vec<i64> = mirrors.iter().filter_map(|mirror| mirror.delay).collect();
+    if delays.is_empty() {
+        return Vec::new();
+    }
+    delays.sort_unstable();
+    let rank = (percentile.clamp(0.0, 100.0) / 100.0 * delays.len() as f64).ceil() as usize;
+    let threshold = delays[rank.max(1) - 1];
+    mirrors
+        .into_iter()
+        .filter(|mirror| mirror.delay.is_some_and(|delay| delay <= threshold))
+        .collect()
+}
+
+/// Keep only mirrors that are fresh according to the `cutoff` of `status`.
+pub(crate) fn filter_by_cutoff(mirrors: Vec<Mirror>, status: &MirrorStatus) -> Vec<Mirror> {
+    mirrors
+        .into_iter()
+        .filter(|mirror| status.fresh(mirror))
+        .collect()
+}
+
+/// The scale of the completion reported for `mirrors`, resolving
+/// [`CompletionScale::Auto`] to a fraction unless some completion exceeds 1.
+pub(crate) fn completion_scale(mirrors: &[Mirror], scale: CompletionScale) -> CompletionScale {
+    match scale {
+        CompletionScale::Auto if mirrors.iter().all(|mirror| mirror.completion_pct <= 1.0) => {
+            CompletionScale::Fraction
+        }
+        CompletionScale::Auto => CompletionScale::Percent,
+        scale => scale,
+    }
+}
+
+/// Keep only mirrors whose completion is at least `completion_percent`.
+///
+/// `completion_percent` is always a percentage between 0 and 100, while the
+/// reported completion is interpreted according to `scale`. The threshold is
+/// converted to the scale of the data rather than the other way around, so a
+/// mirror exactly at the threshold is never lost to rounding. The completion
+/// of the kept mirrors is then normalized to a fraction, which is what the
+/// rest of the pipeline, such as [`reliability_penalty`], expects.
+///
+/// [`reliability_penalty`]: crate::sort::reliability_penalty
+pub(crate) fn filter_by_completion(
+    mirrors: Vec<Mirror>,
+    completion_percent: f64,
+    scale: CompletionScale,
+) -> Vec<Mirror> {
+    let scale = completion_scale(&mirrors, scale);
+    let threshold = if scale == CompletionScale::Fraction {
+        completion_percent / 100.0
+    } else {
+        completion_percent
+    };
+    mirrors
+        .into_iter()
+        .filter(|mirror| mirror.completion_pct >= threshold)
+        .map(|mirror| match scale {
+            CompletionScale::Percent => Mirror {
+                completion_pct: mirror.completion_pct / 100.0,
+                ..mirror
+            },
+            _ => mirror,
+        })
+        .collect()
+}
+
+/// Approximate the hosting provider of `mirror` by the last two labels of its
+/// domain, or by its address when the host is an IP.
+fn provider(mirror: &Mirror) -> String {
+    match mirror.url.host() {
+        Some(Host::Domain(domain)) => {
+            let labels: Vec<_> = domain.trim_end_matches('.').rsplitn(3, '.').collect();
+            labels
+                .iter()
+                .take(2)
+                .rev()
+                .copied()
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+        Some(host) => host.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Keep at most `max` mirrors per hosting provider, preserving order.
+///
+/// The mirror data has no ASN information, so providers are approximated by
+/// their domain (see [`provider`]).
+pub(crate) fn filter_by_max_per_provider(mirrors: Vec<Mirror>, max: usize) -> Vec<Mirror> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    mirrors
+        .into_iter()
+        .filter(|mirror| {
+            let count = counts.entry(provider(mirror)).or_default();
+            *count += 1;
+            *count <= max
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::test_utils::mirror;
+    use url::Url;
+
+    #[test]
+    fn test_dedup_mirrors() {
+        let mirrors: Vec<_> = [
+            "http://mirror.example.com/archlinux",
+            "https://mirror.example.org/archlinux/",
+            "https://Mirror.example.com/archlinux/",
+            "https://mirror.example.com/other/",
+        ]
+        .into_iter()
+        .map(|url| {
+            let url = Url::parse(url).unwrap();
+            Mirror {
+                protocol: url.scheme().to_string(),
+                url,
+                ..mirror()
+            }
+        })
+        .collect();
+        let urls = |protocols: &[Protocol]| {
+            dedup_mirrors(mirrors.clone(), protocols)
+                .into_iter()
+                .map(|mirror| mirror.url.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls(&[Protocol::Https, Protocol::Http]),
+            [
+                "https://mirror.example.org/archlinux/",
+                "https://mirror.example.com/archlinux/",
+                "https://mirror.example.com/other/",
+            ]
+        );
+        assert_eq!(
+            urls(&[Protocol::Http]),
+            [
+                "http://mirror.example.com/archlinux",
+                "https://mirror.example.org/archlinux/",
+                "https://mirror.example.com/other/",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_age() {
+        let now = parse_rfc3339("2023-09-20T12:00:00Z").unwrap();
+        let mirrors = [
+            Some("2023-09-20T11:00:00Z"),
+            Some("2023-09-20T13:30:00+02:00"),
+            Some("2023-09-20T10:29:59Z"),
+            Some("2023-09-20T10:30:00Z"),
+            Some("garbage"),
+            None,
+        ]
+        .into_iter()
+        .map(|last_sync| Mirror {
+            last_sync: last_sync.map(ToString::to_string),
+            ..mirror()
+        })
+        .collect();
+        let last_syncs: Vec<_> = filter_by_age_at(mirrors, 1.5, now)
+            .into_iter()
+            .map(|mirror| mirror.last_sync.unwrap())
+            .collect();
+        assert_eq!(
+            last_syncs,
+            [
+                "2023-09-20T11:00:00Z",
+                "2023-09-20T13:30:00+02:00",
+                "2023-09-20T10:30:00Z"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cutoff_excludes_stale_mirrors() {
+        let status = MirrorStatus {
+            cutoff: 3600,
+            last_check: "2023-09-20T12:00:00.000Z".to_string(),
+            ..Default::default()
+        };
+        let mirrors = ["2023-09-20T11:30:00Z", "2023-09-20T10:00:00Z"]
+            .into_iter()
+            .map(|last_sync| Mirror {
+                last_sync: Some(last_sync.to_string()),
+                ..mirror()
+            })
+            .chain([mirror()])
+            .collect();
+        let last_syncs: Vec<_> = filter_by_cutoff(mirrors, &status)
+            .into_iter()
+            .map(|mirror| mirror.last_sync.unwrap())
+            .collect();
+        assert_eq!(last_syncs, ["2023-09-20T11:30:00Z"]);
+    }
+
+    #[test]
+    fn test_completion_scales_agree() {
+        let mirrors = |completions: [f64; 3]| {
+            completions
+                .into_iter()
+                .map(|completion_pct| Mirror {
+                    completion_pct,
+                    ..mirror()
+                })
+                .collect::<Vec<_>>()
+        };
+        let count = |mirrors, scale| filter_by_completion(mirrors, 90.0, scale).len();
+
+        let fractions = mirrors([1.0, 0.95, 0.5]);
+        let percents = mirrors([100.0, 95.0, 50.0]);
+        assert_eq!(count(fractions.clone(), CompletionScale::Auto), 2);
+        assert_eq!(count(percents.clone(), CompletionScale::Auto), 2);
+        assert_eq!(count(fractions, CompletionScale::Fraction), 2);
+        assert_eq!(count(percents, CompletionScale::Percent), 2);
+    }
+
+    #[test]
+    fn test_completion_normalized_to_fraction() {
+        let completions = |completions: [f64; 2], scale| {
+            let mirrors = completions
+                .into_iter()
+                .map(|completion_pct| Mirror {
+                    completion_pct,
+                    ..mirror()
+                })
+                .collect();
+            filter_by_completion(mirrors, 0.0, scale)
+                .into_iter()
+                .map(|mirror| mirror.completion_pct)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            completions([100.0, 50.0], CompletionScale::Auto),
+            [1.0, 0.5]
+        );
+        assert_eq!(
+            completions([1.0, 0.5], CompletionScale::Percent),
+            [0.01, 0.005]
+        );
+        assert_eq!(completions([1.0, 0.5], CompletionScale::Auto), [1.0, 0.5]);
+    }
+
+    #[test]
+    fn test_fastest_min_https() {
+        let mirrors: Vec<_> = [
+            ("http", Some(100.0)),
+            ("https", None),
+            ("http", Some(90.0)),
+            ("https", Some(40.0)),
+            ("http", Some(80.0)),
+            ("https", Some(50.0)),
+        ]
+        .into_iter()
+        .map(|(protocol, rate)| Mirror {
+            protocol: protocol.to_string(),
+            rate,
+            ..mirror()
+        })
+        .collect();
+        let fastest = |n, min_https| {
+            filter_fastest(mirrors.clone(), n, min_https)
+                .into_iter()
+                .map(|mirror| (mirror.protocol, mirror.rate))
+                .collect::<Vec<_>>()
+        };
+        let http = |rate| ("http".to_string(), Some(rate));
+        let https = |rate| ("https".to_string(), Some(rate));
+        assert_eq!(fastest(3, 0), [http(100.0), http(90.0), http(80.0)]);
+        assert_eq!(fastest(3, 1), [http(100.0), http(90.0), https(50.0)]);
+        assert_eq!(fastest(3, 2), [http(100.0), https(50.0), https(40.0)]);
+        // Unrated https mirrors are never pulled in.
+        assert_eq!(fastest(3, 3), fastest(3, 2));
+        assert_eq!(fastest(1, 2), [https(50.0)]);
+    }
+
+    #[test]
+    fn test_max_per_provider() {
+        let mirrors = [
+            "https://a.mirror.example.com/archlinux/",
+            "https://b.mirror.example.com/archlinux/",
+            "http://example.com/archlinux/",
+            "https://mirror.example.org/archlinux/",
+            "https://192.0.2.1/archlinux/",
+            "http://192.0.2.1/archlinux/",
+        ]
+        .into_iter()
+        .map(|url| Mirror {
+            url: url.parse().unwrap(),
+            ..mirror()
+        })
+        .collect();
+        let urls: Vec<_> = filter_by_max_per_provider(mirrors, 2)
+            .into_iter()
+            .map(|mirror| mirror.url.to_string())
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://a.mirror.example.com/archlinux/",
+                "https://b.mirror.example.com/archlinux/",
+                "https://mirror.example.org/archlinux/",
+                "https://192.0.2.1/archlinux/",
+                "http://192.0.2.1/archlinux/",
+            ]
+        );
+        assert_eq!(
+            filter_by_max_per_provider(vec![mirror(), mirror()], 1).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_completion_threshold_is_inclusive() {
+        let mirrors = [1.0, 0.29, 0.28]
+            .into_iter()
+            .map(|completion_pct| Mirror {
+                completion_pct,
+                ..mirror()
+            })
+            .collect::<Vec<_>>();
+        let completions = |completion_percent| {
+            filter_by_completion(mirrors.clone(), completion_percent, CompletionScale::Auto)
+                .into_iter()
+                .map(|mirror| mirror.completion_pct)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(completions(100.0), [1.0]);
+        assert_eq!(completions(29.0), [1.0, 0.29]);
+    }
+
+    #[test]
+    fn test_protocols() {
+        let arguments =
+            crate::cli::Arguments::parse_from(["reflector-rs", "--protocols", "https,rsync"]);
+        let protocols = arguments.filters.protocols.unwrap();
+        let mirrors: Vec<_> = ["https", "HTTP", "Rsync", "ftp"]
+            .into_iter()
+            .map(|protocol| Mirror {
+                protocol: protocol.to_string(),
+                ..mirror()
+            })
+            .collect();
+        let matched: Vec<_> = mirrors
+            .iter()
+            .filter(|mirror| matches_protocol(mirror, &protocols))
+            .map(|mirror| mirror.protocol.as_str())
+            .collect();
+        assert_eq!(matched, ["https", "Rsync"]);
+    }
+
+    #[test]
+    fn test_default_protocols() {
+        let status = MirrorStatus {
+            urls: ["https", "http", "rsync", "ftp"]
+                .into_iter()
+                .map(|protocol| Mirror {
+                    protocol: protocol.to_string(),
+                    ..mirror()
+                })
+                .collect(),
+            ..MirrorStatus::default()
+        };
+        let protocols = |args: &[&str]| {
+            let filters =
+                crate::cli::Arguments::parse_from([&["reflector-rs"], args].concat()).filters;
+            apply_filters(status.clone(), &filters)
+                .unwrap()
+                .into_iter()
+                .map(|mirror| mirror.protocol)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(protocols(&[]), ["https", "http", "rsync"]);
+        assert_eq!(protocols(&["--protocols", "ftp"]), ["ftp"]);
+        assert_eq!(protocols(&["-p", "https,ftp"]), ["https", "ftp"]);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let filters = |args: &[&str]| {
+            crate::cli::Arguments::parse_from([&["reflector-rs"], args].concat()).filters
+        };
+        let ipv4_only = Mirror {
+            ipv6: false,
+            ..mirror()
+        };
+        let ipv6_only = Mirror {
+            ipv4: false,
+            ..mirror()
+        };
+        assert!(!matches_capabilities(&ipv4_only, &filters(&["--ipv6"])));
+        assert!(matches_capabilities(&ipv6_only, &filters(&["--ipv6"])));
+        assert!(matches_capabilities(&ipv4_only, &filters(&[])));
+        assert!(!matches_capabilities(
+            &ipv6_only,
+            &filters(&["--ipv4", "--ipv6"])
+        ));
+        assert!(matches_capabilities(
+            &mirror(),
+            &filters(&["--ipv4", "--ipv6", "--isos"])
+        ));
+    }
+
+    #[test]
+    fn test_regex() {
+        let mirrors: Vec<_> = [
+            "rsync://mirror.example.de/archlinux/",
+            "https://mirror.example.de/archlinux/",
+            "https://mirror.example.org/archlinux/",
+        ]
+        .into_iter()
+        .map(|url| Mirror {
+            url: url.parse().unwrap(),
+            ..mirror()
+        })
+        .collect();
+        let urls = |include: Option<&str>, exclude: Option<&str>| {
+            let include = include.map(|pattern| Regex::new(pattern).unwrap());
+            let exclude = exclude.map(|pattern| Regex::new(pattern).unwrap());
+            filter_by_regex(
+                mirrors.clone(),
+                MatchField::Url,
+                include.as_ref(),
+                exclude.as_ref(),
+            )
+            .into_iter()
+            .map(|mirror| mirror.url.to_string())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls(None, Some("^rsync")),
+            [
+                "https://mirror.example.de/archlinux/",
+                "https://mirror.example.org/archlinux/"
+            ]
+        );
+        assert_eq!(
+            urls(Some(r"\.de/"), Some("^rsync")),
+            ["https://mirror.example.de/archlinux/"]
+        );
+
+        let filters = |args: &[&str]| {
+            crate::cli::Arguments::parse_from([&["reflector-rs"], args].concat()).filters
+        };
+        assert!(apply_filters(MirrorStatus::default(), &filters(&["-x", "(rsync"])).is_err());
+    }
+
+    #[test]
+    fn test_regex_on_country() {
+        let status = MirrorStatus {
+            urls: ["China", "Germany", "Hong Kong"]
+                .into_iter()
+                .map(|country| Mirror {
+                    country: country.to_string(),
+                    ..mirror()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let countries = |args: &[&str]| {
+            let filters =
+                crate::cli::Arguments::parse_from([&["reflector-rs"], args].concat()).filters;
+            apply_filters(status.clone(), &filters)
+                .unwrap()
+                .into_iter()
+                .map(|mirror| mirror.country)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            countries(&["-x", "China", "--match-field", "country"]),
+            ["Germany", "Hong Kong"]
+        );
+        assert_eq!(
+            countries(&["-i", "^H", "--match-field", "country"]),
+            ["Hong Kong"]
+        );
+        // URLs are matched by default.
+        assert_eq!(countries(&["-x", "China"]).len(), 3);
+    }
+
+    #[test]
+    fn test_latest() {
+        let mirrors = [
+            Some("2023-09-20T09:00:00Z"),
+            None,
+            Some("2023-09-20T11:00:00Z"),
+            Some("2023-09-20T08:00:00Z"),
+            Some("2023-09-20T10:00:00Z"),
+        ]
+        .into_iter()
+        .map(|last_sync| Mirror {
+            last_sync: last_sync.map(ToString::to_string),
+            ..mirror()
+        })
+        .collect();
+        let last_syncs: Vec<_> = filter_latest(mirrors, 2)
+            .into_iter()
+            .map(|mirror| mirror.last_sync.unwrap())
+            .collect();
+        assert_eq!(last_syncs, ["2023-09-20T11:00:00Z", "2023-09-20T10:00:00Z"]);
+    }
+
+    #[test]
+    fn test_latest_ties_in_url_order() {
+        let mirror = |url: &str| Mirror {
+            url: url.parse().unwrap(),
+            last_sync: Some("2023-09-20T10:00:00Z".to_string()),
+            ..mirror()
+        };
+        let urls = |mirrors: Vec<Mirror>| {
+            filter_latest(mirrors, 2)
+                .into_iter()
+                .map(|mirror| mirror.url.to_string())
+                .collect::<Vec<_>>()
+        };
+        let a = mirror("https://a.example.com/archlinux/");
+        let b = mirror("https://b.example.com/archlinux/");
+        let expected = [
+            "https://a.example.com/archlinux/",
+            "https://b.example.com/archlinux/",
+        ];
+        assert_eq!(urls(vec![a.clone(), b.clone()]), expected);
+        assert_eq!(urls(vec![b, a]), expected);
+    }
+
+    #[test]
+    fn test_country() {
+        let filters = |args: &[&str]| {
+            crate::cli::Arguments::parse_from([&["reflector-rs"], args].concat()).filters
+        };
+        let status = MirrorStatus {
+            urls: [("France", "FR"), ("Germany", "DE"), ("Denmark", "DK")]
+                .into_iter()
+                .map(|(country, code)| Mirror {
+                    country: country.to_string(),
+                    country_code: code.to_string(),
+                    ..mirror()
+                })
+                .collect(),
+            ..MirrorStatus::default()
+        };
+        let countries = |args: &[&str]| {
+            apply_filters(status.clone(), &filters(args))
+                .unwrap()
+                .into_iter()
+                .map(|mirror| mirror.country)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            countries(&["--country", "France,de"]),
+            ["France", "Germany"]
+        );
+        assert_eq!(
+            countries(&["-c", "FRANCE", "-c", "dk"]),
+            ["France", "Denmark"]
+        );
+        assert_eq!(
+            countries(&["-c", "de", "--country-expand-neighbors"]),
+            ["France", "Germany", "Denmark"]
+        );
+        assert_eq!(countries(&[]).len(), 3);
+        assert!(countries(&["-c", "Atlantis"]).is_empty());
+        assert_eq!(countries(&["-c", "dk,*"]).len(), 3);
+    }
+
+    #[test]
+    fn test_country_exclude() {
+        let filters = |args: &[&str]| {
+            crate::cli::Arguments::parse_from([&["reflector-rs"], args].concat()).filters
+        };
+        let status = MirrorStatus {
+            urls: [
+                ("China", "CN"),
+                ("Germany", "DE"),
+                ("Russia", "RU"),
+                ("France", "FR"),
+            ]
+            .into_iter()
+            .map(|(country, code)| Mirror {
+                country: country.to_string(),
+                country_code: code.to_string(),
+                ..mirror()
+            })
+            .collect(),
+            ..MirrorStatus::default()
+        };
+        let countries = |args: &[&str]| {
+            apply_filters(status.clone(), &filters(args))
+                .unwrap()
+                .into_iter()
+                .map(|mirror| mirror.country)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            countries(&["--country-exclude", "cn,ru"]),
+            ["Germany", "France"]
+        );
+        assert_eq!(
+            countries(&["-c", "de,cn", "--country-exclude", "China"]),
+            ["Germany"]
+        );
+    }
+
+    #[test]
+    fn test_number() {
+        let filters = |args: &[&str]| {
+            crate::cli::Arguments::parse_from([&["reflector-rs"], args].concat()).filters
+        };
+        let mirrors = vec![mirror(); 10];
+        assert_eq!(
+            apply_number(mirrors.clone(), &filters(&["-n", "3"])).len(),
+            3
+        );
+        assert_eq!(
+            apply_number(mirrors.clone(), &filters(&["-n", "20"])).len(),
+            10
+        );
+        assert_eq!(apply_number(mirrors, &filters(&[])).len(), 10);
+    }
+
+    #[test]
+    fn test_best_score() {
+        let mirrors = [Some(2.5), None, Some(1.0)]
+            .into_iter()
+            .map(|score| Mirror { score, ..mirror() })
+            .collect::<Vec<_>>();
+        let scores = |n| {
+            filter_best_score(mirrors.clone(), n)
+                .into_iter()
+                .map(|mirror| mirror.score)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(scores(3), [Some(1.0), Some(2.5)]);
+        assert_eq!(scores(1), [Some(1.0)]);
+    }
+
+    #[test]
+    fn test_delay_boundary() {
+        let mirrors = [Some(900), Some(901), Some(0), None]
+            .into_iter()
+            .map(|delay| Mirror { delay, ..mirror() })
+            .collect();
+        let delays: Vec<_> = filter_by_delay(mirrors, 0.25)
+            .into_iter()
+            .map(|mirror| mirror.delay)
+            .collect();
+        assert_eq!(delays, [Some(900), Some(0)]);
+    }
+
+    #[test]
+    fn test_delay_percentile_excludes_outliers() {
+        let mirrors = (1..=10)
+            .map(|delay| Mirror {
+                delay: Some(delay * 60),
+                ..mirror()
+            })
+            .chain([mirror()])
+            .collect();
+        let delays: Vec<_> = filter_by_delay_percentile(mirrors, 90.0)
+            .into_iter()
+            .map(|mirror| mirror.delay.unwrap())
+            .collect();
+        assert_eq!(delays, (1..=9).map(|delay| delay * 60).collect::<Vec<_>>());
+    }
+}
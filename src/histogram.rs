@@ -0,0 +1,55 @@
+use std::fmt::Write;
+
+use crate::Mirror;
+
+/// Scores at or above this value are counted in the last bucket.
+const MAX_BUCKET: usize = 10;
+const BAR_WIDTH: usize = 40;
+
+/// Count the mirrors with a reported score in unit-wide buckets, with the
+/// last bucket holding every score of [`MAX_BUCKET`] or more.
+pub(crate) fn score_buckets(mirrors: &[Mirror]) -> [usize; MAX_BUCKET + 1] {
+    let mut buckets = [0; MAX_BUCKET + 1];
+    for score in mirrors.iter().filter_map(|mirror| mirror.score) {
+        buckets[(score.max(0.0) as usize).min(MAX_BUCKET)] += 1;
+    }
+    buckets
+}
+
+/// Render the score distribution of `mirrors` as a text histogram.
+///
+/// Lower scores are better.
+pub(crate) fn render_score_histogram(mirrors: &[Mirror]) -> String {
+    let buckets = score_buckets(mirrors);
+    let max = buckets.iter().copied().max().unwrap_or_default().max(1);
+    let mut out = String::new();
+    for (bucket, &count) in buckets.iter().enumerate() {
+        let label = if bucket == MAX_BUCKET {
+            format!("{bucket}+")
+        } else {
+            format!("{bucket}-{}", bucket + 1)
+        };
+        let bar = "#".repeat(count * BAR_WIDTH / max);
+        writeln!(out, "{label:>5} | {bar} {count}").unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::mirror;
+
+    #[test]
+    fn test_score_buckets() {
+        let mirrors: Vec<_> = [Some(0.2), Some(0.9), Some(1.0), Some(3.5), Some(42.0), None]
+            .into_iter()
+            .map(|score| Mirror { score, ..mirror() })
+            .collect();
+        assert_eq!(score_buckets(&mirrors), [2, 1, 0, 1, 0, 0, 0, 0, 0, 0, 1]);
+        let histogram = render_score_histogram(&mirrors);
+        assert_eq!(histogram.lines().count(), MAX_BUCKET + 1);
+        assert!(histogram.starts_with("  0-1 | "));
+        assert!(histogram.ends_with(" 1\n"));
+    }
+}
@@ -0,0 +1,2273 @@
+//! Retrieve, filter and sort the latest Arch Linux mirrors.
+//!
+//! The binary is a thin wrapper around [`run`]. Other programs can select
+//! mirrors from an already retrieved [`MirrorStatus`] with [`select_mirrors`],
+//! or filter, rate and sort them as configured by [`Options`] with
+//! [`reflect`].
+use std::{
+    env::var_os,
+    fmt,
+    fs::TryLockError,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use dirs::cache_dir;
+use log::{debug, info, warn, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use reqwest::{header::CONTENT_ENCODING, Client, ClientBuilder, IntoUrl, Proxy, Response};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    fs::{create_dir_all, File},
+    io,
+};
+use url::Url;
+
+use lazy_static::lazy_static;
+lazy_static! {
+    static ref DEFAULT_URL: Url = Url::parse("https://archlinux.org/mirrors/status/json/").unwrap();
+}
+mod cli;
+mod connectivity;
+mod country;
+mod filter;
+mod histogram;
+mod mirrorlist;
+mod options;
+mod pattern;
+mod rate;
+mod sort;
+#[cfg(test)]
+mod test_utils;
+mod timestamp;
+
+pub use cli::{Arguments, CountryOrder, Filters, Protocol, SortType};
+pub use options::{Options, OptionsBuilder};
+pub use pattern::RegexError;
+
+const NAME: &str = "Reflector-rs";
+
+/// Version of the Mirror Status API schema this program understands.
+pub const SUPPORTED_VERSION: i64 = 3;
+
+/// The mirror data reported by the Arch Linux Mirror Status API.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MirrorStatus {
+    pub cutoff: i64,
+    pub last_check: String,
+    pub num_checks: i64,
+    pub check_frequency: i64,
+    pub urls: Vec<Mirror>,
+    pub version: i64,
+}
+
+impl MirrorStatus {
+    /// When the Mirror Status API last checked the mirrors, if `last_check`
+    /// is a valid timestamp.
+    fn last_check_time(&self) -> Option<SystemTime> {
+        timestamp::parse_rfc3339(&self.last_check)
+    }
+
+    /// How long ago the mirrors were last checked, if known.
+    fn dataset_age(&self) -> Option<Duration> {
+        Some(
+            SystemTime::now()
+                .duration_since(self.last_check_time()?)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Whether `mirror` synchronized within `cutoff` seconds of `last_check`,
+    /// the staleness criterion used by the Mirror Status API.
+    ///
+    /// Mirrors whose freshness cannot be determined are not considered fresh.
+    fn fresh(&self, mirror: &Mirror) -> bool {
+        let (Some(last_check), Some(last_sync)) = (
+            self.last_check_time(),
+            mirror
+                .last_sync
+                .as_deref()
+                .and_then(timestamp::parse_rfc3339),
+        ) else {
+            return false;
+        };
+        last_check.duration_since(last_sync).unwrap_or_default()
+            <= Duration::from_secs(self.cutoff.max(0) as u64)
+    }
+}
+
+/// A mirror as reported by the Arch Linux Mirror Status API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mirror {
+    pub url: Url,
+    pub protocol: String,
+    pub last_sync: Option<String>,
+    pub completion_pct: f64,
+    pub delay: Option<i64>,
+    pub duration_avg: Option<f64>,
+    pub duration_stddev: Option<f64>,
+    pub score: Option<f64>,
+    pub active: bool,
+    pub country: String,
+    pub country_code: String,
+    pub isos: bool,
+    pub ipv4: bool,
+    pub ipv6: bool,
+    pub details: String,
+    /// Download rate in bytes per second, if measured by [`rate`].
+    #[serde(skip)]
+    pub rate: Option<f64>,
+}
+
+/// Number of bytes of response bodies downloaded, shared by its clones.
+///
+/// Counters compare by the number of bytes they counted.
+#[derive(Clone, Debug, Default)]
+struct ByteCounter(Arc<AtomicU64>);
+
+impl ByteCounter {
+    /// Count `bytes` more bytes.
+    fn add(&self, bytes: u64) {
+        self.0.fetch_add(bytes, atomic::Ordering::Relaxed);
+    }
+
+    /// The number of bytes counted so far.
+    fn get(&self) -> u64 {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+}
+
+impl PartialEq for ByteCounter {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl Eq for ByteCounter {}
+
+/// How HTTP requests are sent: their timeouts, how often they are retried,
+/// and the proxy they are sent through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct HttpOptions {
+    /// Time allowed to establish the TCP connection.
+    connection: Duration,
+    /// Time allowed for the TLS handshake, on top of `connection`.
+    tls: Duration,
+    /// Time allowed for the whole request, including the transfer.
+    download: Duration,
+    /// Number of retries of a request failing transiently.
+    retries: u32,
+    /// Proxy for all requests, instead of those given by the environment.
+    proxy: Option<Url>,
+    /// Bytes downloaded with these options, across the mirror status and
+    /// the rating of mirrors.
+    downloaded: ByteCounter,
+}
+
+impl From<&cli::Arguments> for HttpOptions {
+    fn from(arguments: &cli::Arguments) -> Self {
+        HttpOptions {
+            connection: Duration::from_secs(arguments.connection_timeout),
+            tls: Duration::from_secs(arguments.tls_timeout),
+            download: Duration::from_secs(arguments.download_timeout),
+            retries: arguments.retries,
+            proxy: arguments.proxy.clone(),
+            downloaded: ByteCounter::default(),
+        }
+    }
+}
+
+/// Configure a client builder with the options in `http`.
+///
+/// reqwest bounds TCP connection and TLS handshake by a single connect
+/// timeout, so it is set to the sum of both. gzip and brotli responses are
+/// requested and decompressed transparently, so the cache stores plain JSON.
+/// Without an explicit proxy, reqwest honors `HTTP_PROXY`, `HTTPS_PROXY` and
+/// `ALL_PROXY`; a proxy given here overrides them.
+fn client_builder(http: &HttpOptions) -> reqwest::Result<ClientBuilder> {
+    let builder = Client::builder()
+        .gzip(true)
+        .brotli(true)
+        .connect_timeout(http.connection + http.tls)
+        .timeout(http.download);
+    match &http.proxy {
+        Some(proxy) => Ok(builder.proxy(Proxy::all(proxy.clone())?)),
+        None => Ok(builder),
+    }
+}
+
+/// Delay before the first retry of a request, doubled for each further retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Send GET request to `url` with reqwest.
+///
+/// Timeouts, connection errors and server errors are retried up to
+/// `http.retries` times with exponential backoff. The response to the
+/// last attempt is returned as is, so server errors must still be checked.
+async fn get_with_timeout<T: IntoUrl>(url: T, http: HttpOptions) -> reqwest::Result<Response> {
+    let client = client_builder(&http)?.build()?;
+    let url = url.into_url()?;
+    let mut backoff = RETRY_BACKOFF;
+    let mut retries = http.retries;
+    loop {
+        let result = client.get(url.clone()).send().await;
+        let transient = match &result {
+            Ok(response) => response.status().is_server_error(),
+            Err(err) => err.is_timeout() || err.is_connect() || err.is_request(),
+        };
+        if !transient || retries == 0 {
+            return result;
+        }
+        retries -= 1;
+        info!("retrying {url} in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+/// Examing whether `timeout` is passed since `time`.
+fn invalidated(time: SystemTime, timeout: Duration) -> bool {
+    SystemTime::now().duration_since(time).unwrap_or_default() > timeout
+}
+
+/// Path of the cache file `name` in `dir`, or in the user cache directory if
+/// `dir` is `None`.
+fn cache_file(name: &Path, dir: Option<&Path>) -> PathBuf {
+    let mut cache_dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => cache_dir().unwrap_or_else(|| PathBuf::from("/tmp/cache")),
+    };
+    cache_dir.push(name);
+    cache_dir
+}
+
+/// Path of the cache file `name` in `dir`, or in the user cache directory if
+/// `dir` is `None`, creating its parent directories.
+async fn get_cache_file(name: &Path, dir: Option<&Path>) -> io::Result<PathBuf> {
+    let cache_dir = cache_file(name, dir);
+    if let Some(parent) = cache_dir.parent() {
+        create_dir_all(parent).await.map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "failed to create cache directory {}: {err}",
+                    parent.display()
+                ),
+            )
+        })?;
+    }
+    Ok(cache_dir)
+}
+
+#[derive(Debug, Error)]
+pub enum GetMirrorStatusError {
+    #[error("I/O error: {0}")]
+    IO(#[from] io::Error),
+    #[error("Failed to retrieve: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("Failed to serialize/deserialize: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Response has unsupported content encoding: {0}")]
+    UnsupportedEncoding(String),
+    #[error("Invalid file URL: {0}")]
+    InvalidFileUrl(Url),
+}
+
+/// Any error that makes the program fail.
+#[derive(Error)]
+pub enum AppError {
+    #[error("Failed to get mirror status: {0}")]
+    MirrorStatus(#[from] GetMirrorStatusError),
+    #[error("{0}")]
+    InvalidRegex(#[from] pattern::RegexError),
+    #[error("Failed to write mirrorlist: {0}")]
+    Output(#[from] io::Error),
+    #[error("Failed to rate mirrors: {0}")]
+    Rating(reqwest::Error),
+    #[error("Failed to set up logging: {0}")]
+    Logger(#[from] SetLoggerError),
+    #[error("Timed out after {} seconds", .0.as_secs())]
+    TimedOut(Duration),
+    #[error("Unsupported mirror status version {0}, expected {SUPPORTED_VERSION}")]
+    UnsupportedVersion(i64),
+    #[error("{}; relax them or pass \"--allow-empty\"", no_mirrors_left(.0))]
+    NoMirrors(Vec<&'static str>),
+    #[error(
+        "\"--fastest\" would rate every mirror; narrow them down with \"--country\", \
+         \"--protocols\", \"--latest\", \"--score\" or \"--number\", or pass \"--force\""
+    )]
+    FastestNotNarrowed,
+}
+
+/// `main` reports the error it returns by its `Debug` representation, so it
+/// shows the message rather than the variant.
+impl fmt::Debug for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Check that `mirror_status` has the schema version this program supports.
+///
+/// Other versions may name or mean fields differently, so they are reported
+/// as an error if `strict`, and with a warning otherwise.
+fn check_version(mirror_status: &MirrorStatus, strict: bool) -> Result<(), AppError> {
+    if mirror_status.version == SUPPORTED_VERSION {
+        return Ok(());
+    }
+    let err = AppError::UnsupportedVersion(mirror_status.version);
+    if strict {
+        return Err(err);
+    }
+    warn!("{err}; the mirrors may be misinterpreted");
+    Ok(())
+}
+
+/// Age of the mirror status beyond which it is reported as outdated.
+const MAX_DATASET_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A warning that the mirrors in `mirror_status` were last checked longer
+/// ago than [`MAX_DATASET_AGE`], as with an old cache or an unmaintained
+/// source.
+fn dataset_age_warning(mirror_status: &MirrorStatus) -> Option<String> {
+    let age = mirror_status.dataset_age()?;
+    (age > MAX_DATASET_AGE).then(|| {
+        format!(
+            "the mirror status was last checked {} hours ago",
+            age.as_secs() / 3600
+        )
+    })
+}
+
+/// Format `bytes` with a binary unit, e.g. `1.5 MiB`.
+fn format_size(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size:.0} {}", UNITS[unit])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// What a run reports on stderr once the mirrors are selected.
+#[derive(Debug, PartialEq, Serialize)]
+struct Summary {
+    selected: usize,
+    total: usize,
+    /// The active filters, as `option` or `option=value`.
+    filters: Vec<String>,
+    /// Number of selected mirrors that were rated.
+    rated: usize,
+    /// Sum of the rates of the rated selected mirrors, in bytes per second.
+    rate_sum: Option<f64>,
+    /// Average rate of the rated selected mirrors, in bytes per second.
+    rate_average: Option<f64>,
+    /// Bytes downloaded for the mirror status and the rating of mirrors.
+    downloaded: u64,
+}
+
+impl Summary {
+    /// Summarize that `selected` of `total` mirrors were kept by `filters`,
+    /// after downloading `downloaded` bytes.
+    ///
+    /// Only the rated mirrors count towards the rates.
+    fn new(selected: &[Mirror], total: usize, filters: &Filters, downloaded: u64) -> Self {
+        let filters = filters
+            .active()
+            .into_iter()
+            .map(|(option, value)| {
+                let option = option.trim_start_matches('-');
+                value.map_or_else(|| option.to_string(), |value| format!("{option}={value}"))
+            })
+            .collect();
+        let rates: Vec<_> = selected.iter().filter_map(|mirror| mirror.rate).collect();
+        let rate_sum = (!rates.is_empty()).then(|| rates.iter().sum::<f64>());
+        Summary {
+            selected: selected.len(),
+            total,
+            filters,
+            rated: rates.len(),
+            rate_sum,
+            rate_average: rate_sum.map(|sum| sum / rates.len() as f64),
+            downloaded,
+        }
+    }
+}
+
+/// Write `summary` as a line of JSON with the JSON output format, and as a
+/// line of text otherwise, e.g. `selected 12 of 487 mirrors (filters:
+/// country=DE)`, followed by the rates of the mirrors if any was rated and
+/// by the bytes downloaded if any.
+fn write_summary(
+    mut writer: impl std::io::Write,
+    summary: &Summary,
+    format: cli::OutputFormat,
+) -> std::io::Result<()> {
+    if format == cli::OutputFormat::Json {
+        serde_json::to_writer(&mut writer, summary)?;
+        return writeln!(writer);
+    }
+    write!(
+        writer,
+        "selected {} of {} mirrors",
+        summary.selected, summary.total
+    )?;
+    if !summary.filters.is_empty() {
+        write!(writer, " (filters: {})", summary.filters.join(" "))?;
+    }
+    if let (Some(sum), Some(average)) = (summary.rate_sum, summary.rate_average) {
+        write!(
+            writer,
+            "; {} rated at {}/s in total, {}/s on average",
+            summary.rated,
+            format_size(sum),
+            format_size(average)
+        )?;
+    }
+    if summary.downloaded > 0 {
+        write!(
+            writer,
+            "; downloaded {}",
+            format_size(summary.downloaded as f64)
+        )?;
+    }
+    writeln!(writer)
+}
+
+/// Describe that no mirror is left after applying the `filters`.
+fn no_mirrors_left(filters: &[&str]) -> String {
+    if filters.is_empty() {
+        "No mirrors meet the criteria".to_string()
+    } else {
+        format!("No mirrors meet the criteria of {}", filters.join(", "))
+    }
+}
+
+/// Fail if no mirror is left to output, naming the active filters, unless
+/// "--allow-empty" is given, in which case only warn.
+fn check_not_empty(mirrors: &[Mirror], arguments: &Arguments) -> Result<(), AppError> {
+    if !mirrors.is_empty() {
+        return Ok(());
+    }
+    let filters: Vec<_> = arguments
+        .filters
+        .active()
+        .into_iter()
+        .map(|(option, _)| option)
+        .collect();
+    if arguments.allow_empty {
+        warn!("{}", no_mirrors_left(&filters));
+        return Ok(());
+    }
+    Err(AppError::NoMirrors(filters))
+}
+
+/// Request the mirror status from `url`, returning the successful response
+/// before its body is read.
+///
+/// The client transparently decodes the encodings it advertises, so any
+/// remaining `Content-Encoding` means the body cannot be parsed.
+async fn fetch_response(url: &Url, http: HttpOptions) -> Result<Response, GetMirrorStatusError> {
+    info!("retrieving mirror status from {url}");
+    let response = get_with_timeout(url.clone(), http)
+        .await?
+        .error_for_status()?;
+    if let Some(encoding) = response.headers().get(CONTENT_ENCODING) {
+        if encoding != "identity" {
+            return Err(GetMirrorStatusError::UnsupportedEncoding(
+                String::from_utf8_lossy(encoding.as_bytes()).into_owned(),
+            ));
+        }
+    }
+    Ok(response)
+}
+
+/// Fetch and parse the mirror status from `url`.
+async fn fetch_mirror_status(
+    url: &Url,
+    http: HttpOptions,
+) -> Result<MirrorStatus, GetMirrorStatusError> {
+    let downloaded = http.downloaded.clone();
+    let body = fetch_response(url, http).await?.bytes().await?;
+    downloaded.add(body.len() as u64);
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn read_cache(cache_path: &Path) -> Result<MirrorStatus, GetMirrorStatusError> {
+    Ok(serde_json::from_reader(
+        File::open(cache_path).await?.into_std().await,
+    )?)
+}
+
+async fn write_cache(
+    cache_path: &Path,
+    mirror_status: &MirrorStatus,
+) -> Result<(), GetMirrorStatusError> {
+    Ok(serde_json::to_writer(
+        File::create(cache_path).await?.into_std().await,
+        mirror_status,
+    )?)
+}
+
+/// Response header by which a source marks its answer to a `since` query as
+/// holding only the mirrors changed since then.
+const DELTA_HEADER: &str = "x-mirror-status-since";
+
+/// Update `cached` with the mirrors reported by `url` as changed since it was
+/// last checked, using a `since` query parameter.
+///
+/// Only responses carrying [`DELTA_HEADER`] are merged into the cache. Any
+/// other response is taken to be the full list, as sent by sources ignoring
+/// the parameter, and replaces the cached one, dropping removed mirrors.
+async fn fetch_mirror_status_since(
+    url: &Url,
+    http: HttpOptions,
+    cached: MirrorStatus,
+) -> Result<MirrorStatus, GetMirrorStatusError> {
+    let mut delta_url = url.clone();
+    delta_url
+        .query_pairs_mut()
+        .append_pair("since", &cached.last_check);
+    let downloaded = http.downloaded.clone();
+    let response = fetch_response(&delta_url, http).await?;
+    let is_delta = response.headers().contains_key(DELTA_HEADER);
+    let body = response.bytes().await?;
+    downloaded.add(body.len() as u64);
+    let delta: MirrorStatus = serde_json::from_slice(&body)?;
+    if !is_delta {
+        info!("{url} returned every mirror, replacing the cache");
+        return Ok(delta);
+    }
+
+    let mut urls = cached.urls;
+    for mirror in delta.urls {
+        match urls.iter_mut().find(|cached| cached.url == mirror.url) {
+            Some(cached) => *cached = mirror,
+            None => urls.push(mirror),
+        }
+    }
+    Ok(MirrorStatus { urls, ..delta })
+}
+
+/// A 64-bit FNV-1a hash of `bytes`, which unlike the standard library hasher
+/// is guaranteed to be stable across builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Name of the file caching the mirror status retrieved from `url`, relative
+/// to the cache directory.
+///
+/// Other sources than the default URL are cached under a hash of their URL,
+/// keeping the file name short whatever the length of the URL.
+fn cache_name(url: &Url) -> PathBuf {
+    if url == DEFAULT_URL.deref() {
+        PathBuf::from("mirrorstatus.json")
+    } else {
+        let filename = format!("{:016x}.json", fnv1a(url.as_str().as_bytes()));
+        Path::new(NAME).join(filename)
+    }
+}
+
+/// Path of the file caching the mirror status retrieved from `url`, in `dir`
+/// if given, creating its parent directories.
+async fn get_cache_path(url: &Url, dir: Option<&Path>) -> io::Result<PathBuf> {
+    get_cache_file(&cache_name(url), dir).await
+}
+
+/// Describe the file caching the mirror status retrieved from `url`, as
+/// its path followed by whether it is fresh, stale or missing.
+///
+/// Unlike [`get_cache_path`], this creates no directories.
+fn describe_cache(url: &Url, cache: &CacheOptions) -> String {
+    let cache_path = cache_file(&cache_name(url), cache.dir.as_deref());
+    let state = match cache_path
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+    {
+        Ok(mtime) if invalidated(mtime, cache.timeout) => "stale",
+        Ok(_) => "fresh",
+        Err(_) => "missing",
+    };
+    format!("{} ({state})", cache_path.display())
+}
+
+/// Environment variable naming a local JSON file to read the mirror status
+/// from, taking precedence over both the cache and the network.
+const FIXTURE_VAR: &str = "REFLECTOR_RS_FIXTURE";
+
+/// How the mirror status cache is used.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct CacheOptions {
+    /// Read the mirror status from this file, bypassing the cache and the
+    /// network entirely. Set from [`FIXTURE_VAR`].
+    fixture: Option<PathBuf>,
+    /// Directory of the cache, instead of the user cache directory.
+    dir: Option<PathBuf>,
+    /// Age after which the cache is no longer used.
+    timeout: Duration,
+    /// Always try to fetch, but fall back to the cache, however stale it is,
+    /// if fetching fails.
+    refresh: bool,
+    /// Always fetch, never reading the cache but still writing to it.
+    no_cache: bool,
+    /// Never write fetched data to the cache.
+    readonly: bool,
+    /// Update an existing cache with only the changed mirrors, falling back
+    /// to a full fetch if that fails.
+    incremental: bool,
+    /// How long to wait for another process to release the cache.
+    lock_timeout: Duration,
+}
+
+impl From<&cli::Arguments> for CacheOptions {
+    fn from(arguments: &cli::Arguments) -> Self {
+        CacheOptions::with_fixture(arguments, var_os(FIXTURE_VAR).map(PathBuf::from))
+    }
+}
+
+impl CacheOptions {
+    /// The cache options given by `arguments`, with the fixture file
+    /// `fixture` rather than the one named by [`FIXTURE_VAR`].
+    fn with_fixture(arguments: &cli::Arguments, fixture: Option<PathBuf>) -> Self {
+        CacheOptions {
+            fixture,
+            dir: arguments.cache_dir.clone(),
+            timeout: Duration::from_secs(arguments.cache_timeout),
+            refresh: arguments.refresh,
+            no_cache: arguments.no_cache,
+            readonly: arguments.cache_readonly,
+            incremental: arguments.incremental,
+            lock_timeout: CACHE_LOCK_TIMEOUT,
+        }
+    }
+}
+
+/// How long to wait for another process to release the cache by default.
+const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval at which a locked cache is checked again.
+const CACHE_LOCK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Take an exclusive advisory lock guarding the cache at `cache_path`,
+/// waiting at most `timeout` for other processes to release it.
+///
+/// The lock is held on a sibling `.lock` file until the returned file is
+/// dropped. Returns `None` if the lock could not be taken in time, and an
+/// error if the lock file cannot be opened or locked at all, as in a
+/// read-only cache directory.
+async fn lock_cache(cache_path: &Path, timeout: Duration) -> io::Result<Option<std::fs::File>> {
+    let mut lock_name = cache_path.file_name().unwrap_or_default().to_os_string();
+    lock_name.push(".lock");
+    let lock_path = cache_path.with_file_name(lock_name);
+    let file = std::fs::File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(Some(file)),
+            Err(TryLockError::WouldBlock) if tokio::time::Instant::now() < deadline => {
+                tokio::time::sleep(CACHE_LOCK_INTERVAL).await;
+            }
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+    }
+}
+
+/// Smooth the rates of `mirrors` with those of earlier runs, as
+/// [`rate::smooth_rates`] with `alpha`, using the rate cache according to
+/// `cache`.
+///
+/// Failing to read or write the rate cache only warns, and a corrupt cache
+/// is replaced.
+async fn smooth_cached_rates(mirrors: &mut [Mirror], alpha: f64, cache: &CacheOptions) {
+    let name = Path::new(rate::RATE_CACHE_NAME);
+    let path = if cache.readonly {
+        cache_file(name, cache.dir.as_deref())
+    } else {
+        match get_cache_file(name, cache.dir.as_deref()).await {
+            Ok(path) => path,
+            Err(err) => {
+                warn!("{err}");
+                cache_file(name, cache.dir.as_deref())
+            }
+        }
+    };
+    let read = if cache.no_cache {
+        Err(io::ErrorKind::NotFound.into())
+    } else {
+        tokio::fs::read(&path).await
+    };
+    let mut history = match read {
+        Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|err| {
+            warn!("discarding corrupt rate cache {}: {err}", path.display());
+            rate::RateHistory::new()
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => rate::RateHistory::new(),
+        Err(err) => {
+            warn!("failed to read rate cache {}: {err}", path.display());
+            rate::RateHistory::new()
+        }
+    };
+    rate::smooth_rates(mirrors, &mut history, alpha);
+    if !cache.readonly {
+        let written = match serde_json::to_vec(&history) {
+            Ok(contents) => tokio::fs::write(&path, contents).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = written {
+            warn!("failed to write rate cache {}: {err}", path.display());
+        }
+    }
+}
+
+/// Get the mirror status from `url`, using the cached copy according to
+/// `cache`.
+///
+/// A fixture file takes precedence over the cache, which in turn takes
+/// precedence over the network while it is fresh. `file` URLs are read
+/// directly, bypassing the cache.
+///
+/// Concurrent invocations take turns to use the cache. If it stays locked by
+/// another process for longer than `cache.lock_timeout`, the mirror status is
+/// fetched without reading or writing the cache. A cache that cannot be
+/// locked, such as one in a read-only directory, or that is only read with
+/// `cache.readonly`, is used without the lock.
+async fn get_mirror_status(
+    http: HttpOptions,
+    cache: &CacheOptions,
+    url: &Url,
+) -> Result<(MirrorStatus, Option<SystemTime>), GetMirrorStatusError> {
+    if let Some(fixture) = &cache.fixture {
+        info!("reading mirror status from fixture {}", fixture.display());
+        return Ok((read_cache(fixture).await?, None));
+    }
+    if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|()| GetMirrorStatusError::InvalidFileUrl(url.clone()))?;
+        info!("reading mirror status from {}", path.display());
+        let mtime = tokio::fs::metadata(&path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .map_err(|err| {
+                io::Error::new(err.kind(), format!("cannot read {}: {err}", path.display()))
+            })?;
+        return Ok((read_cache(&path).await?, Some(mtime)));
+    }
+    let cache_path = get_cache_path(url, cache.dir.as_deref()).await?;
+    let _lock = if cache.readonly {
+        None
+    } else {
+        match lock_cache(&cache_path, cache.lock_timeout).await {
+            Ok(Some(lock)) => Some(lock),
+            Ok(None) => {
+                warn!(
+                    "cache {} is in use by another process, fetching without it",
+                    cache_path.display()
+                );
+                return Ok((
+                    fetch_mirror_status(url, http).await?,
+                    Some(SystemTime::now()),
+                ));
+            }
+            Err(err) => {
+                info!(
+                    "using cache {} without locking it: {err}",
+                    cache_path.display()
+                );
+                None
+            }
+        }
+    };
+
+    // A cache whose modification time is unavailable is treated as missing.
+    let mut mtime = cache_path
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    let invalid = mtime.is_none_or(|mtime| invalidated(mtime, cache.timeout));
+    if !(invalid || cache.refresh || cache.no_cache) {
+        match read_cache(&cache_path).await {
+            Ok(mirror_status) => {
+                info!("using cached mirror status {}", cache_path.display());
+                return Ok((mirror_status, mtime));
+            }
+            // A cache truncated by an interrupted write would otherwise
+            // break every later run, so it is discarded and fetched again.
+            Err(GetMirrorStatusError::Serde(err)) => {
+                warn!("discarding corrupt cache {}: {err}", cache_path.display());
+                if let Err(err) = tokio::fs::remove_file(&cache_path).await {
+                    warn!("failed to remove cache {}: {err}", cache_path.display());
+                }
+                mtime = None;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let fetched = if cache.incremental && !cache.no_cache && mtime.is_some() {
+        let updated = match read_cache(&cache_path).await {
+            Ok(cached) => fetch_mirror_status_since(url, http.clone(), cached).await,
+            Err(err) => Err(err),
+        };
+        match updated {
+            Ok(mirror_status) => Ok(mirror_status),
+            Err(err) => {
+                warn!("incremental update failed, fetching all mirrors: {err}");
+                fetch_mirror_status(url, http).await
+            }
+        }
+    } else {
+        fetch_mirror_status(url, http).await
+    };
+    let mirror_status = match fetched {
+        Ok(mirror_status) => mirror_status,
+        Err(err) if cache.refresh && mtime.is_some() => {
+            let cached = read_cache(&cache_path).await?;
+            match cached.dataset_age() {
+                Some(age) => warn!(
+                    "failed to refresh mirror status, using stale cache checked {} minutes ago: {err}",
+                    age.as_secs() / 60
+                ),
+                None => warn!("failed to refresh mirror status, using stale cache: {err}"),
+            }
+            return Ok((cached, mtime));
+        }
+        Err(err) => return Err(err),
+    };
+
+    if !cache.readonly {
+        if let Err(err) = write_cache(&cache_path, &mirror_status).await {
+            warn!("failed to write cache {}: {err}", cache_path.display());
+        }
+    }
+
+    Ok((mirror_status, Some(SystemTime::now())))
+}
+
+/// Logger printing the records of this crate to standard error.
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let level = match record.level() {
+                Level::Warn => "warning".to_string(),
+                level => level.as_str().to_lowercase(),
+            };
+            eprintln!("{level}: {}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// The logger for `--verbose` given `verbose` times, or for `--quiet`.
+fn get_logger(verbose: u8, quiet: bool) -> Logger {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    Logger { level }
+}
+
+/// Install the logger configured by `arguments`.
+pub fn init_logger(arguments: &cli::Arguments) -> Result<(), SetLoggerError> {
+    let logger = get_logger(arguments.verbose, arguments.quiet);
+    log::set_max_level(logger.level);
+    log::set_boxed_logger(Box::new(logger))
+}
+
+/// Select mirrors from `status` by `filters`, sorted by `sort`.
+///
+/// This is the part of [`run`] that needs no network access: mirrors are
+/// neither verified nor rated, so "--fastest" is ignored and sorting by rate
+/// keeps the order of the mirrors. Ties are broken by the default
+/// "--protocol-sort-weights". Fails if the `--include` or `--exclude`
+/// expression is invalid.
+pub fn select_mirrors(
+    status: MirrorStatus,
+    filters: &Filters,
+    sort: Option<SortType>,
+) -> Result<Vec<Mirror>, RegexError> {
+    let mirrors = filter::apply_filters(status, filters)?;
+    let mut mirrors = filter::apply_limits(mirrors, filters);
+    if let Some(sort) = sort {
+        let countries = filter::country_tokens(filters);
+        let protocols = [
+            Protocol::Https,
+            Protocol::Http,
+            Protocol::Rsync,
+            Protocol::Ftp,
+        ];
+        let overrides = filters.country_alias.as_deref().unwrap_or_default();
+        sort::sort_mirrors(
+            &mut mirrors,
+            sort,
+            countries.as_deref(),
+            cli::CountryOrder::Name,
+            overrides,
+            &protocols,
+        );
+    }
+    Ok(filter::apply_number(mirrors, filters))
+}
+
+/// Filter, rate and sort the mirrors in `status` as configured by `options`.
+///
+/// Unlike [`select_mirrors`], mirrors are rated when `options` keep the
+/// fastest ones or sort them by rate, which needs network access. Fails if
+/// the `include` or `exclude` expression is invalid, or if rating fails.
+///
+/// ```
+/// use reflector_rs::{reflect, MirrorStatus, Options, Protocol};
+///
+/// let options = Options::builder()
+///     .age(24.0)
+///     .protocols([Protocol::Https])
+///     .fastest(5)
+///     .build();
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// let mirrors = runtime
+///     .block_on(reflect(MirrorStatus::default(), &options))
+///     .unwrap();
+/// assert!(mirrors.is_empty());
+/// ```
+pub async fn reflect(status: MirrorStatus, options: &Options) -> Result<Vec<Mirror>, AppError> {
+    let mirrors = filter::apply_filters(status, &options.filters)?;
+    let mut mirrors = filter::apply_limits(mirrors, &options.filters);
+    if options.needs_rating() {
+        rate::rate_mirrors(
+            &mut mirrors,
+            options.http.clone(),
+            options.threads,
+            options.probe.clone(),
+            |_, _| (),
+        )
+        .await
+        .map_err(AppError::Rating)?;
+    }
+    if let Some(fastest) = options.filters.fastest {
+        let min_https = options.filters.fastest_min_https.unwrap_or(0);
+        mirrors = filter::filter_fastest(mirrors, fastest, min_https);
+    }
+    if let Some(sort) = options.sort.clone() {
+        let countries = filter::country_tokens(&options.filters);
+        sort::sort_mirrors(
+            &mut mirrors,
+            sort,
+            countries.as_deref(),
+            options.country_order_by,
+            options.filters.country_alias.as_deref().unwrap_or_default(),
+            &options.protocol_sort_weights,
+        );
+    }
+    Ok(filter::apply_number(mirrors, &options.filters))
+}
+
+/// Retrieve the mirror status as configured by `arguments`, then print the
+/// selected mirrors or save them to "--save".
+///
+/// Fails with [`AppError::TimedOut`] if this takes longer than
+/// "--timeout-total". As the mirrorlist is only written at the end, and
+/// saved atomically, nothing is written then.
+pub async fn run(arguments: &Arguments) -> Result<(), AppError> {
+    match arguments.timeout_total.map(Duration::from_secs) {
+        Some(budget) => tokio::time::timeout(budget, run_pipeline(arguments))
+            .await
+            .map_err(|_| AppError::TimedOut(budget))?,
+        None => run_pipeline(arguments).await,
+    }
+}
+
+/// The steps of [`run`], without its overall time limit.
+async fn run_pipeline(arguments: &Arguments) -> Result<(), AppError> {
+    let mut arguments = arguments.clone();
+    if arguments.filters.countries_from_locale && arguments.filters.country.is_none() {
+        arguments.filters.country = country::system_locale_country().map(|country| vec![country]);
+    }
+    let arguments = &arguments;
+    if arguments.filters.fastest.is_some() && !arguments.filters.narrowed() {
+        if !arguments.force {
+            return Err(AppError::FastestNotNarrowed);
+        }
+        warn!("rating every mirror, which may take a long time");
+    }
+    if arguments.print_cache_path {
+        let cache = CacheOptions::from(arguments);
+        println!("{}", describe_cache(&arguments.url, &cache));
+        return Ok(());
+    }
+    if let Some(path) = arguments
+        .save
+        .as_deref()
+        .or(arguments.merge_existing.as_deref())
+        .filter(|_| !arguments.list_countries)
+    {
+        mirrorlist::check_writable(path)?;
+    }
+    let options = Options::from(arguments);
+    let (mirror_status, retrieved) = get_mirror_status(
+        options.http.clone(),
+        &CacheOptions::from(arguments),
+        &arguments.url,
+    )
+    .await?;
+    check_version(&mirror_status, arguments.strict)?;
+    if let Some(warning) = dataset_age_warning(&mirror_status) {
+        warn!("{warning}");
+    }
+    if arguments.list_countries {
+        if arguments.output_format == cli::OutputFormat::Json {
+            print!("{}", country::render_country_json(&mirror_status.urls));
+            return Ok(());
+        }
+        print!("{}", country::render_country_table(&mirror_status.urls));
+        if arguments.histogram {
+            println!();
+            print!("{}", histogram::render_score_histogram(&mirror_status.urls));
+        }
+        return Ok(());
+    }
+    let header = mirrorlist::Header {
+        command: std::iter::once(env!("CARGO_PKG_NAME").to_string())
+            .chain(std::env::args().skip(1))
+            .collect::<Vec<_>>()
+            .join(" "),
+        when: SystemTime::now(),
+        from: arguments.url.clone(),
+        retrieved,
+        last_check: mirror_status.last_check.clone(),
+        width: arguments.header_width,
+        mirror_path: arguments.mirror_path.clone(),
+    };
+    let total = mirror_status.urls.len();
+    debug!("{total} mirrors retrieved");
+    let mut mirrors = filter::apply_filters(mirror_status, &arguments.filters)?;
+    debug!("{} mirrors after filtering", mirrors.len());
+    if arguments.dedup {
+        mirrors = filter::dedup_mirrors(mirrors, &arguments.protocol_sort_weights);
+        debug!("{} mirrors after deduplication", mirrors.len());
+    }
+    let mirrors = if arguments.verify_connectivity {
+        let families = connectivity::Families {
+            ipv4: arguments.filters.ipv4,
+            ipv6: arguments.filters.ipv6,
+        };
+        let mirrors = connectivity::verify_connectivity(
+            mirrors,
+            families,
+            options.http.connection,
+            options.threads,
+        )
+        .await;
+        debug!("{} mirrors reachable", mirrors.len());
+        mirrors
+    } else {
+        mirrors
+    };
+    let mut mirrors = filter::apply_limits(mirrors, &arguments.filters);
+    debug!("{} mirrors after limiting", mirrors.len());
+    if arguments.needs_rating() {
+        let progress = |rated, total| {
+            if !arguments.quiet {
+                rate::report_progress(rated, total);
+            }
+        };
+        rate::rate_mirrors(
+            &mut mirrors,
+            options.http.clone(),
+            options.threads,
+            options.probe.clone(),
+            progress,
+        )
+        .await
+        .map_err(AppError::Rating)?;
+        if let Some(alpha) = arguments.rate_ema {
+            smooth_cached_rates(&mut mirrors, alpha, &CacheOptions::from(arguments)).await;
+        }
+    }
+    if let Some(fastest) = arguments.filters.fastest {
+        let min_https = arguments.filters.fastest_min_https.unwrap_or(0);
+        mirrors = filter::filter_fastest(mirrors, fastest, min_https);
+        debug!("{} fastest mirrors kept", mirrors.len());
+    }
+    if let Some(keys) = &arguments.sort_expr {
+        sort::sort_by_keys(&mut mirrors, keys, arguments.country_order_by);
+    } else if let Some(sort) = arguments.sort.clone() {
+        let countries = filter::country_tokens(&arguments.filters);
+        sort::sort_mirrors(
+            &mut mirrors,
+            sort,
+            countries.as_deref(),
+            arguments.country_order_by,
+            arguments
+                .filters
+                .country_alias
+                .as_deref()
+                .unwrap_or_default(),
+            &arguments.protocol_sort_weights,
+        );
+    }
+    if arguments.prefer_ipv6 {
+        sort::prefer_ipv6(&mut mirrors);
+    }
+    let mirrors = filter::apply_number(mirrors, &arguments.filters);
+    check_not_empty(&mirrors, arguments)?;
+    match &arguments.save {
+        Some(path) if arguments.verify_save => mirrorlist::save_verified(
+            path,
+            &mirrors,
+            &header,
+            arguments.output_format,
+            arguments.append,
+        )?,
+        Some(path) if arguments.append => mirrorlist::append_mirrorlist(path, &mirrors, &header)?,
+        Some(path) => {
+            mirrorlist::save_mirrorlist(path, &mirrors, &header, arguments.output_format)?
+        }
+        None => match &arguments.merge_existing {
+            Some(path) => mirrorlist::merge_mirrorlist(path, &mirrors, &header)?,
+            None => mirrorlist::write_output(
+                &mirrors,
+                &header,
+                arguments.output_format,
+                std::io::stdout().lock(),
+            )?,
+        },
+    }
+    if !arguments.quiet {
+        write_summary(
+            std::io::stderr().lock(),
+            &Summary::new(
+                &mirrors,
+                total,
+                &arguments.filters,
+                options.http.downloaded.get(),
+            ),
+            arguments.output_format,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::test_utils::{self, json_response, response, serve, serve_status, temp_path};
+
+    #[test]
+    fn test_app_error_from() {
+        let error = AppError::from(GetMirrorStatusError::UnsupportedEncoding(
+            "zstd".to_string(),
+        ));
+        assert!(matches!(error, AppError::MirrorStatus(_)), "{error:?}");
+        let error = AppError::from(pattern::Regex::new("(").unwrap_err());
+        assert!(matches!(error, AppError::InvalidRegex(_)), "{error:?}");
+        let error = AppError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(matches!(error, AppError::Output(_)), "{error:?}");
+        let error = AppError::Rating(Client::new().get("http://").build().unwrap_err());
+        assert_eq!(
+            format!("{error:?}"),
+            format!("{error}"),
+            "main reports errors by their Debug representation"
+        );
+    }
+
+    #[test]
+    fn test_get_logger() {
+        let record = |level| {
+            Metadata::builder()
+                .level(level)
+                .target("reflector_rs")
+                .build()
+        };
+        let logger = get_logger(0, false);
+        assert!(logger.enabled(&record(Level::Warn)));
+        assert!(!logger.enabled(&record(Level::Info)));
+        assert!(get_logger(2, false).enabled(&record(Level::Debug)));
+        assert!(!get_logger(2, false).enabled(&record(Level::Trace)));
+        assert!(get_logger(5, false).enabled(&record(Level::Trace)));
+        assert!(!get_logger(0, true).enabled(&record(Level::Warn)));
+        let dependency = Metadata::builder()
+            .level(Level::Warn)
+            .target("hyper")
+            .build();
+        assert!(!logger.enabled(&dependency));
+        logger.log(&Record::builder().metadata(record(Level::Warn)).build());
+    }
+
+    fn http(secs: u64) -> HttpOptions {
+        HttpOptions {
+            connection: Duration::from_secs(secs),
+            tls: Duration::from_secs(secs),
+            download: Duration::from_secs(secs),
+            retries: 0,
+            proxy: None,
+            downloaded: ByteCounter::default(),
+        }
+    }
+
+    #[test]
+    fn test_last_check_time() {
+        let status = MirrorStatus {
+            last_check: "2023-09-20T10:26:05.921Z".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            status.last_check_time(),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1695205565921))
+        );
+        assert!(status.dataset_age().unwrap() > Duration::from_secs(86400));
+        let warning = dataset_age_warning(&status).unwrap();
+        assert!(warning.ends_with(" hours ago"), "{warning}");
+
+        let recent = MirrorStatus {
+            last_check: timestamp::format_utc(SystemTime::now())
+                .replacen(' ', "T", 1)
+                .replace(" UTC", "Z"),
+            ..Default::default()
+        };
+        assert!(recent.dataset_age().unwrap() < Duration::from_secs(60));
+        assert_eq!(dataset_age_warning(&recent), None);
+
+        let malformed = MirrorStatus {
+            last_check: "yesterday".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(malformed.last_check_time(), None);
+        assert_eq!(malformed.dataset_age(), None);
+        assert_eq!(dataset_age_warning(&malformed), None);
+    }
+
+    /// Each option is read from its own argument, so options of the same
+    /// type, such as the three timeouts, must not be mixed up.
+    #[test]
+    fn test_http_options_from_arguments() {
+        let arguments = cli::Arguments::parse_from([
+            NAME,
+            "--connection-timeout",
+            "1",
+            "--tls-timeout",
+            "2",
+            "--download-timeout",
+            "3",
+            "--cache-timeout",
+            "4",
+        ]);
+        assert_eq!(
+            HttpOptions::from(&arguments),
+            HttpOptions {
+                connection: Duration::from_secs(1),
+                tls: Duration::from_secs(2),
+                download: Duration::from_secs(3),
+                retries: 3,
+                proxy: None,
+                downloaded: ByteCounter::default(),
+            }
+        );
+        assert_eq!(
+            CacheOptions::from(&arguments).timeout,
+            Duration::from_secs(4)
+        );
+    }
+
+    #[test]
+    fn test_client_builder_timeouts() {
+        let http = HttpOptions {
+            connection: Duration::from_secs(1),
+            tls: Duration::from_secs(2),
+            download: Duration::from_secs(4),
+            retries: 0,
+            proxy: None,
+            downloaded: ByteCounter::default(),
+        };
+        let builder = format!("{:?}", client_builder(&http).unwrap());
+        assert!(builder.contains("connect_timeout: 3s"), "{builder}");
+        assert!(builder.contains(" timeout: 4s"), "{builder}");
+        assert!(client_builder(&http).unwrap().build().is_ok());
+    }
+
+    #[test]
+    fn test_client_builder_proxy() {
+        let arguments = cli::Arguments::parse_from([NAME, "--proxy", "http://proxy.example:3128"]);
+        let http = HttpOptions::from(&arguments);
+        assert_eq!(
+            http.proxy.as_ref().map(Url::as_str),
+            Some("http://proxy.example:3128/")
+        );
+        let builder = format!("{:?}", client_builder(&http).unwrap());
+        assert!(builder.contains("proxy.example:3128"), "{builder}");
+        assert!(client_builder(&http).unwrap().build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_mirror_status() -> Result<(), GetMirrorStatusError> {
+        let dir = temp_path("network");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let result = get_mirror_status(http(5), &cache, DEFAULT_URL.deref()).await;
+        let _ = std::fs::remove_dir_all(&dir);
+        dbg!(result?.0.urls);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_file_names() -> io::Result<()> {
+        let long = Url::parse(&format!("https://example.com/{}/json/", "a".repeat(500))).unwrap();
+        let short = Url::parse("https://example.com/json/").unwrap();
+        let dir = temp_path("names");
+        let name = |path: PathBuf| path.file_name().unwrap().to_str().unwrap().to_string();
+        let long = name(get_cache_path(&long, Some(&dir)).await?);
+        let short = name(get_cache_path(&short, Some(&dir)).await?);
+        assert_ne!(long, short);
+        for name in [long, short] {
+            assert_eq!(name.len(), "0123456789abcdef.json".len(), "{name}");
+            assert!(name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'.'));
+        }
+        assert_eq!(
+            name(get_cache_path(&DEFAULT_URL, Some(&dir)).await?),
+            "mirrorstatus.json"
+        );
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        std::fs::remove_dir_all(&dir)
+    }
+
+    #[tokio::test]
+    async fn test_refresh_falls_back_to_cache() -> Result<(), GetMirrorStatusError> {
+        let url = Url::parse("http://127.0.0.1:9/refresh-fallback/json/").unwrap();
+        let dir = temp_path("refresh-fallback");
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        let cached = test_utils::status();
+        serde_json::to_writer(std::fs::File::create(&cache_path)?, &cached)?;
+        let cache_mtime = cache_path.metadata()?.modified()?;
+
+        let result = get_mirror_status(
+            http(1),
+            &CacheOptions {
+                dir: Some(dir.clone()),
+                timeout: Duration::from_secs(300),
+                refresh: true,
+                ..Default::default()
+            },
+            &url,
+        )
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        let (mirror_status, mtime) = result?;
+        assert_eq!(mirror_status, cached);
+        assert_eq!(mtime, Some(cache_mtime));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fresh_cache_is_used() -> Result<(), GetMirrorStatusError> {
+        let url = Url::parse("http://127.0.0.1:9/fresh-cache/json/").unwrap();
+        let dir = temp_path("fresh-cache");
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        let cached = test_utils::status();
+        write_cache(&cache_path, &cached).await?;
+        let cache_mtime = cache_path.metadata()?.modified()?;
+
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(300),
+            ..Default::default()
+        };
+        let result = get_mirror_status(http(1), &cache, &url).await;
+        std::fs::remove_dir_all(&dir)?;
+        let (mirror_status, mtime) = result?;
+        assert_eq!(mirror_status, cached);
+        assert_eq!(mtime, Some(cache_mtime));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_readonly_cache_is_not_written() -> Result<(), GetMirrorStatusError> {
+        let served = test_utils::status();
+        let url = serve_status(&served, "readonly/json/").await;
+        let dir = temp_path("readonly");
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+
+        let result = get_mirror_status(
+            http(5),
+            &CacheOptions {
+                dir: Some(dir.clone()),
+                timeout: Duration::from_secs(300),
+                readonly: true,
+                ..Default::default()
+            },
+            &url,
+        )
+        .await;
+        let written = cache_path.exists();
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(result?.0, served);
+        assert!(!written);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_readonly_cache_dir() -> Result<(), GetMirrorStatusError> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let served = test_utils::status();
+        let url = serve_status(&served, "readonly-dir/json/").await;
+        let dir = temp_path("readonly-dir");
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        let stale = MirrorStatus::default();
+        write_cache(&cache_path, &stale).await?;
+        let cache_dir = cache_path.parent().unwrap();
+        std::fs::set_permissions(cache_dir, std::fs::Permissions::from_mode(0o555))?;
+
+        // The stale cache is refetched without being overwritten.
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::ZERO,
+            readonly: true,
+            ..Default::default()
+        };
+        let fetched = get_mirror_status(http(5), &cache, &url).await;
+        let cached = read_cache(&cache_path).await;
+        // The server has stopped, but "--refresh" falls back to the cache.
+        let refresh = CacheOptions {
+            refresh: true,
+            ..cache
+        };
+        let fallback = get_mirror_status(http(1), &refresh, &url).await;
+        std::fs::set_permissions(cache_dir, std::fs::Permissions::from_mode(0o755))?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(fetched?.0, served);
+        assert_eq!(cached?, stale);
+        assert_eq!(fallback?.0, stale);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_cache_dir() -> Result<(), GetMirrorStatusError> {
+        let served = test_utils::status();
+        let url = serve_status(&served, "cache-dir/json/").await;
+        let dir = temp_path("cache");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(300),
+            ..Default::default()
+        };
+        get_mirror_status(http(5), &cache, &url).await?;
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        assert!(cache_path.starts_with(&dir));
+        assert_eq!(read_cache(&cache_path).await?, served);
+        // The cache is used from now on, as the server is gone.
+        assert_eq!(get_mirror_status(http(5), &cache, &url).await?.0, served);
+        std::fs::remove_dir_all(&dir)?;
+
+        let not_a_dir = temp_path("file");
+        std::fs::write(&not_a_dir, "")?;
+        let cache = CacheOptions {
+            dir: Some(not_a_dir.join("cache")),
+            ..cache
+        };
+        let result = get_mirror_status(http(5), &cache, &url).await;
+        std::fs::remove_file(&not_a_dir)?;
+        assert!(
+            matches!(result, Err(GetMirrorStatusError::IO(_))),
+            "{result:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_cache_ignores_fresh_cache() -> Result<(), GetMirrorStatusError> {
+        let served = test_utils::status();
+        let url = serve_status(&served, "no-cache/json/").await;
+        let dir = temp_path("no-cache");
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        write_cache(&cache_path, &MirrorStatus::default()).await?;
+
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(300),
+            no_cache: true,
+            ..Default::default()
+        };
+        let result = get_mirror_status(http(5), &cache, &url).await;
+        let cached = read_cache(&cache_path).await;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(result?.0, served);
+        assert_eq!(cached?, served);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_count_downloaded_bytes() -> Result<(), GetMirrorStatusError> {
+        let served = test_utils::status();
+        let status_len = serde_json::to_vec(&served)?.len() as u64;
+        let url = serve_status(&served, "downloaded/json/").await;
+        let dir = temp_path("downloaded");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            no_cache: true,
+            ..Default::default()
+        };
+        let http = http(5);
+        let result = get_mirror_status(http.clone(), &cache, &url).await;
+        std::fs::remove_dir_all(&dir)?;
+        result?;
+        assert_eq!(http.downloaded.get(), status_len);
+
+        let mut mirrors = Vec::new();
+        for _ in 0..2 {
+            let url = serve(vec![response("200 OK", &[], &[0; 4096])]).await;
+            mirrors.push(Mirror {
+                url,
+                ..test_utils::mirror()
+            });
+        }
+        let probe = rate::ProbeOptions {
+            repo: "core".to_string(),
+            bytes: None,
+            samples: 1,
+            require_valid_tls: false,
+        };
+        rate::rate_mirrors(&mut mirrors, http.clone(), 1, probe, |_, _| ()).await?;
+        assert!(mirrors.iter().all(|mirror| mirror.rate.is_some()));
+        assert_eq!(http.downloaded.get(), status_len + 2 * 4096);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_failures() -> Result<(), GetMirrorStatusError> {
+        let served = test_utils::status();
+        let unavailable = || response("503 Service Unavailable", &[], b"");
+        let url = serve(vec![
+            unavailable(),
+            unavailable(),
+            json_response(&serde_json::to_vec(&served)?),
+        ])
+        .await;
+        let retrying = HttpOptions {
+            retries: 2,
+            ..http(5)
+        };
+        assert_eq!(fetch_mirror_status(&url, retrying.clone()).await?, served);
+
+        let url = serve(vec![unavailable(), unavailable()]).await;
+        let once = HttpOptions {
+            retries: 1,
+            ..http(5)
+        };
+        let result = fetch_mirror_status(&url, once).await;
+        assert!(
+            matches!(&result, Err(GetMirrorStatusError::Reqwest(err)) if err.status().is_some_and(|status| status.as_u16() == 503)),
+            "{result:?}"
+        );
+
+        let url = serve(vec![
+            response("404 Not Found", &[], b""),
+            json_response(b"{}"),
+        ])
+        .await;
+        let result = fetch_mirror_status(&url, retrying).await;
+        assert!(
+            matches!(&result, Err(GetMirrorStatusError::Reqwest(err)) if err.status().is_some_and(|status| status.as_u16() == 404)),
+            "{result:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_content_encoding() {
+        let url = serve(vec![response(
+            "200 OK",
+            &[
+                ("Content-Type", "application/json"),
+                ("Content-Encoding", "zstd"),
+            ],
+            b"\x28\xb5\x2f\xfd",
+        )])
+        .await;
+        let result = fetch_mirror_status(&url, http(5)).await;
+        assert!(
+            matches!(&result, Err(GetMirrorStatusError::UnsupportedEncoding(encoding)) if encoding == "zstd"),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_smooth_cached_rates() {
+        let dir = temp_path("rate-ema");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let smoothed = |rate, cache: CacheOptions| async move {
+            let mut mirrors = vec![Mirror {
+                rate: Some(rate),
+                ..test_utils::mirror()
+            }];
+            smooth_cached_rates(&mut mirrors, 0.3, &cache).await;
+            mirrors[0].rate.unwrap().round()
+        };
+        assert_eq!(smoothed(1000.0, cache.clone()).await, 1000.0);
+        assert!(dir.join(rate::RATE_CACHE_NAME).is_file());
+        assert_eq!(smoothed(10.0, cache.clone()).await, 703.0);
+        let readonly = CacheOptions {
+            readonly: true,
+            ..cache.clone()
+        };
+        assert_eq!(smoothed(10.0, readonly.clone()).await, 495.0);
+        assert_eq!(smoothed(10.0, readonly).await, 495.0);
+        let no_cache = CacheOptions {
+            no_cache: true,
+            ..cache.clone()
+        };
+        assert_eq!(smoothed(10.0, no_cache).await, 10.0);
+        std::fs::write(dir.join(rate::RATE_CACHE_NAME), "{").unwrap();
+        assert_eq!(smoothed(10.0, cache.clone()).await, 10.0);
+        assert_eq!(smoothed(1000.0, cache).await, 307.0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_reflect_limit_order() {
+        let ok = || async { serve(vec![response("200 OK", &[], &[0; 1024])]).await };
+        let failing = serve(vec![response("404 Not Found", &[], b"")]).await;
+        let unused = Url::parse("http://127.0.0.1:1/").unwrap();
+        let rsync = Url::parse("rsync://mirror.example.org/archlinux/").unwrap();
+        let mirror = |url: &Url, score, last_sync: &str| Mirror {
+            url: url.clone(),
+            protocol: url.scheme().to_string(),
+            score: Some(score),
+            last_sync: Some(format!("2023-09-20T{last_sync}:00Z")),
+            ..test_utils::mirror()
+        };
+        let (first, second) = (ok().await, ok().await);
+        let status = MirrorStatus {
+            urls: vec![
+                mirror(&first, 1.0, "10:00"),
+                mirror(&second, 2.0, "09:00"),
+                mirror(&failing, 3.0, "11:00"),
+                // Dropped by "--score", but kept first by "--latest".
+                mirror(&unused, 4.0, "10:30"),
+                // Dropped by "--latest", but kept first by "--score".
+                mirror(&rsync, 0.5, "07:00"),
+                // Dropped by "--max-per-asn" as the fifth mirror on 127.0.0.1,
+                // but kept first by "--score" and "--latest".
+                mirror(&unused, 0.1, "12:00"),
+            ],
+            ..test_utils::status()
+        };
+        let arguments = cli::Arguments::parse_from([
+            NAME,
+            "--max-per-asn",
+            "4",
+            "--score",
+            "4",
+            "--latest",
+            "3",
+            "--fastest",
+            "3",
+            "--sort",
+            "score",
+            "--number",
+            "2",
+            "--download-timeout",
+            "1",
+        ]);
+        // "--max-per-asn" keeps the first four mirrors and rsync, "--score"
+        // drops the fourth, "--latest" rsync, and the failing mirror is
+        // slowest among the fastest, so "--number" drops it after sorting.
+        let urls: Vec<_> = reflect(status, &Options::from(&arguments))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|mirror| mirror.url)
+            .collect();
+        assert_eq!(urls, [first, second]);
+    }
+
+    #[tokio::test]
+    async fn test_run_fastest_requires_narrowing() {
+        let run_with = |args: &'static [&'static str]| async move {
+            let url = serve_status(&test_utils::status(), "x/json/").await;
+            let dir = temp_path(&format!("fastest-{}", args.join("")));
+            let arguments = cli::Arguments::parse_from(
+                [
+                    &[
+                        NAME,
+                        "--url",
+                        url.as_str(),
+                        "--cache-dir",
+                        dir.to_str().unwrap(),
+                        "--allow-empty",
+                        "--quiet",
+                    ],
+                    args,
+                ]
+                .concat(),
+            );
+            let result = run(&arguments).await;
+            let _ = std::fs::remove_dir_all(&dir);
+            result
+        };
+        let result = run_with(&["--fastest", "5"]).await;
+        assert!(
+            matches!(result, Err(AppError::FastestNotNarrowed)),
+            "{result:?}"
+        );
+        run_with(&["--fastest", "5", "--force"]).await.unwrap();
+        run_with(&["--fastest", "5", "--country", "de"])
+            .await
+            .unwrap();
+        run_with(&["--fastest", "5", "--latest", "20"])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout_total() {
+        // A server that accepts connections but never responds.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let dir = temp_path("deadline");
+        let arguments = cli::Arguments::parse_from([
+            NAME,
+            "--url",
+            &url,
+            "--cache-dir",
+            dir.to_str().unwrap(),
+            "--download-timeout",
+            "30",
+            "--timeout-total",
+            "1",
+        ]);
+        let started = std::time::Instant::now();
+        let result = run(&arguments).await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(
+            matches!(result, Err(AppError::TimedOut(budget)) if budget == Duration::from_secs(1)),
+            "{result:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_write_summary() {
+        let arguments = cli::Arguments::parse_from([
+            NAME,
+            "-c",
+            "DE",
+            "-p",
+            "https",
+            "--completion-percent",
+            "0",
+        ]);
+        let summary = |selected, total, filters: &Filters| {
+            let mut stderr = Vec::new();
+            let summary = Summary::new(&vec![test_utils::mirror(); selected], total, filters, 0);
+            write_summary(&mut stderr, &summary, cli::OutputFormat::Pacman).unwrap();
+            String::from_utf8(stderr).unwrap()
+        };
+        assert_eq!(
+            summary(12, 487, &arguments.filters),
+            "selected 12 of 487 mirrors (filters: country=DE protocols=https)\n"
+        );
+
+        let arguments = cli::Arguments::parse_from([NAME, "--isos"]);
+        assert_eq!(
+            summary(0, 3, &arguments.filters),
+            "selected 0 of 3 mirrors (filters: completion-percent=100 isos)\n"
+        );
+    }
+
+    #[test]
+    fn test_summary_rates() {
+        let arguments = cli::Arguments::parse_from([NAME, "--completion-percent", "0"]);
+        let mirrors: Vec<_> = [Some(1024.0), None, Some(2.5 * 1024.0 * 1024.0)]
+            .into_iter()
+            .map(|rate| Mirror {
+                rate,
+                ..test_utils::mirror()
+            })
+            .collect();
+        let summary = Summary::new(&mirrors, 10, &arguments.filters, 3 * 1024 * 1024);
+        assert_eq!(summary.rated, 2);
+        assert_eq!(summary.rate_sum, Some(2622464.0));
+        assert_eq!(summary.rate_average, Some(1311232.0));
+
+        let mut stderr = Vec::new();
+        write_summary(&mut stderr, &summary, cli::OutputFormat::Raw).unwrap();
+        assert_eq!(
+            String::from_utf8(stderr).unwrap(),
+            "selected 3 of 10 mirrors; 2 rated at 2.5 MiB/s in total, 1.3 MiB/s on average; \
+             downloaded 3.0 MiB\n"
+        );
+        let mut stderr = Vec::new();
+        write_summary(&mut stderr, &summary, cli::OutputFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&stderr).unwrap();
+        assert_eq!(json["rated"], 2);
+        assert_eq!(json["rate_average"], 1311232.0);
+        assert_eq!(json["filters"], serde_json::json!([]));
+        assert_eq!(json["downloaded"], 3 * 1024 * 1024);
+
+        let unrated = Summary::new(&[test_utils::mirror()], 1, &arguments.filters, 0);
+        assert_eq!((unrated.rate_sum, unrated.rate_average), (None, None));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0.0), "0 B");
+        assert_eq!(format_size(1023.0), "1023 B");
+        assert_eq!(format_size(1536.0), "1.5 KiB");
+        assert_eq!(format_size(3.0 * 1024.0 * 1024.0 * 1024.0), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_check_version() {
+        let status = |version| MirrorStatus {
+            version,
+            ..Default::default()
+        };
+        assert!(check_version(&status(SUPPORTED_VERSION), true).is_ok());
+        let newer: MirrorStatus = serde_json::from_str(
+            r#"{"cutoff": 3600, "last_check": "2023-09-20T10:08:33Z", "num_checks": 1,
+                "check_frequency": 600, "urls": [], "version": 4}"#,
+        )
+        .unwrap();
+        assert!(check_version(&newer, false).is_ok());
+        let err = check_version(&newer, true).unwrap_err();
+        assert!(matches!(err, AppError::UnsupportedVersion(4)));
+        assert_eq!(
+            err.to_string(),
+            format!("Unsupported mirror status version 4, expected {SUPPORTED_VERSION}")
+        );
+    }
+
+    #[test]
+    fn test_contradicting_filters() {
+        let arguments = |args: &[&str]| {
+            cli::Arguments::parse_from([&[NAME, "--age", "1", "--delay", "2"], args].concat())
+        };
+        let status = MirrorStatus {
+            urls: vec![Mirror {
+                // Synchronized long ago, but with a low reported delay.
+                last_sync: Some("2000-01-01T00:00:00Z".to_string()),
+                delay: Some(600),
+                ..test_utils::mirror()
+            }],
+            ..Default::default()
+        };
+        let mirrors = filter::apply_filters(status, &arguments(&[]).filters).unwrap();
+        let err = check_not_empty(&mirrors, &arguments(&[])).unwrap_err();
+        assert!(
+            matches!(&err, AppError::NoMirrors(filters) if filters == &["--age", "--delay", "--completion-percent"])
+        );
+        assert_eq!(
+            err.to_string(),
+            "No mirrors meet the criteria of --age, --delay, --completion-percent; \
+            relax them or pass \"--allow-empty\""
+        );
+        assert!(check_not_empty(&mirrors, &arguments(&["--allow-empty"])).is_ok());
+        assert!(check_not_empty(&[test_utils::mirror()], &arguments(&[])).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unlockable_cache_is_read() -> Result<(), GetMirrorStatusError> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let url = Url::parse("http://127.0.0.1:9/unlockable/json/").unwrap();
+        let dir = temp_path("unlockable");
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        let cached = test_utils::status();
+        write_cache(&cache_path, &cached).await?;
+        let cache_dir = cache_path.parent().unwrap();
+        std::fs::set_permissions(cache_dir, std::fs::Permissions::from_mode(0o555))?;
+
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(300),
+            ..Default::default()
+        };
+        let unlocked = get_mirror_status(http(1), &cache, &url).await;
+        let readonly = CacheOptions {
+            readonly: true,
+            ..cache
+        };
+        let read = get_mirror_status(http(1), &readonly, &url).await;
+        std::fs::set_permissions(cache_dir, std::fs::Permissions::from_mode(0o755))?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(unlocked?.0, cached);
+        assert_eq!(read?.0, cached);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_cache_access() -> Result<(), GetMirrorStatusError> {
+        let served = test_utils::status();
+        // Only one request is served, so the second call must use the cache.
+        let url = serve_status(&served, "concurrent/json/").await;
+        let dir = temp_path("concurrent");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(300),
+            lock_timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let (cache, url) = (cache.clone(), url.clone());
+                tokio::spawn(async move { get_mirror_status(http(5), &cache, &url).await })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap()?.0, served);
+        }
+
+        // A cache locked for too long is bypassed and left untouched.
+        let url = serve_status(&served, "locked/json/").await;
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        let _lock = lock_cache(&cache_path, Duration::ZERO).await?.unwrap();
+        let cache = CacheOptions {
+            lock_timeout: Duration::from_millis(100),
+            ..cache
+        };
+        assert_eq!(get_mirror_status(http(5), &cache, &url).await?.0, served);
+        assert!(!cache_path.exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_cache_is_fetched_again() -> Result<(), GetMirrorStatusError> {
+        let served = test_utils::status();
+        let url = serve_status(&served, "corrupt/json/").await;
+        let dir = temp_path("corrupt");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(300),
+            ..Default::default()
+        };
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        let truncated = serde_json::to_vec(&served)?;
+        std::fs::write(&cache_path, &truncated[..truncated.len() / 2])?;
+
+        let (mirror_status, _) = get_mirror_status(http(5), &cache, &url).await?;
+        let cached = read_cache(&cache_path).await;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(mirror_status, served);
+        assert_eq!(cached?, served);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_describe_cache() -> io::Result<()> {
+        let dir = temp_path("describe");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(300),
+            ..Default::default()
+        };
+        let missing = describe_cache(&DEFAULT_URL, &cache);
+        assert!(!dir.exists());
+        let path = get_cache_file(Path::new("mirrorstatus.json"), Some(&dir)).await?;
+        std::fs::write(&path, "{}")?;
+        let fresh = describe_cache(&DEFAULT_URL, &cache);
+        let stale = CacheOptions {
+            timeout: Duration::ZERO,
+            ..cache
+        };
+        std::thread::sleep(Duration::from_millis(10));
+        let stale = describe_cache(&DEFAULT_URL, &stale);
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(missing, format!("{} (missing)", path.display()));
+        assert_eq!(fresh, format!("{} (fresh)", path.display()));
+        assert_eq!(stale, format!("{} (stale)", path.display()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gzip_response() -> Result<(), GetMirrorStatusError> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let served = MirrorStatus {
+            urls: vec![test_utils::mirror()],
+            ..test_utils::status()
+        };
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&serde_json::to_vec(&served)?)?;
+        let url = serve(vec![response(
+            "200 OK",
+            &[
+                ("Content-Type", "application/json"),
+                ("Content-Encoding", "gzip"),
+            ],
+            &encoder.finish()?,
+        )])
+        .await
+        .join("gzip/json/")
+        .unwrap();
+        let dir = temp_path("gzip");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            timeout: Duration::from_secs(300),
+            ..Default::default()
+        };
+        let (mirror_status, _) = get_mirror_status(http(5), &cache, &url).await?;
+        let cached = std::fs::read(get_cache_path(&url, Some(&dir)).await?)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(mirror_status, served);
+        assert_eq!(serde_json::from_slice::<MirrorStatus>(&cached)?, served);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incremental_update_merges_into_cache() -> Result<(), GetMirrorStatusError> {
+        let mirror = |url: &str| Mirror {
+            url: url.parse().unwrap(),
+            ..test_utils::mirror()
+        };
+        let cached = MirrorStatus {
+            last_check: "2023-09-20T10:00:00.000Z".to_string(),
+            urls: vec![
+                mirror("https://a.example.com/archlinux/"),
+                mirror("https://b.example.com/archlinux/"),
+            ],
+            ..Default::default()
+        };
+        let delta = MirrorStatus {
+            last_check: "2023-09-20T11:00:00.000Z".to_string(),
+            urls: vec![
+                Mirror {
+                    score: Some(1.0),
+                    ..mirror("https://b.example.com/archlinux/")
+                },
+                mirror("https://c.example.com/archlinux/"),
+            ],
+            ..Default::default()
+        };
+        let url = serve(vec![response(
+            "200 OK",
+            &[(DELTA_HEADER, &cached.last_check)],
+            &serde_json::to_vec(&delta)?,
+        )])
+        .await
+        .join("incremental/json/")
+        .unwrap();
+        let dir = temp_path("incremental");
+        serde_json::to_writer(
+            std::fs::File::create(get_cache_path(&url, Some(&dir)).await?)?,
+            &cached,
+        )?;
+
+        let result = get_mirror_status(
+            http(5),
+            &CacheOptions {
+                dir: Some(dir.clone()),
+                incremental: true,
+                ..Default::default()
+            },
+            &url,
+        )
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        let (mirror_status, _) = result?;
+        assert_eq!(mirror_status.last_check, delta.last_check);
+        let urls: Vec<_> = mirror_status
+            .urls
+            .iter()
+            .map(|mirror| (mirror.url.as_str(), mirror.score))
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                ("https://a.example.com/archlinux/", None),
+                ("https://b.example.com/archlinux/", Some(1.0)),
+                ("https://c.example.com/archlinux/", None),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incremental_full_response_replaces_cache() -> Result<(), GetMirrorStatusError> {
+        let mirror = |url: &str| Mirror {
+            url: url.parse().unwrap(),
+            ..test_utils::mirror()
+        };
+        let before = MirrorStatus {
+            last_check: "2023-09-20T10:00:00.000Z".to_string(),
+            urls: vec![
+                mirror("https://a.example.com/archlinux/"),
+                mirror("https://b.example.com/archlinux/"),
+            ],
+            ..Default::default()
+        };
+        let after = MirrorStatus {
+            last_check: "2023-09-20T11:00:00.000Z".to_string(),
+            urls: vec![mirror("https://b.example.com/archlinux/")],
+            ..Default::default()
+        };
+        let url = serve(vec![
+            json_response(&serde_json::to_vec(&before)?),
+            json_response(&serde_json::to_vec(&after)?),
+        ])
+        .await
+        .join("incremental-full/json/")
+        .unwrap();
+        let dir = temp_path("incremental-full");
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            incremental: true,
+            ..Default::default()
+        };
+        let first = get_mirror_status(http(5), &cache, &url).await;
+        let second = get_mirror_status(http(5), &cache, &url).await;
+        let cached = read_cache(&get_cache_path(&url, Some(&dir)).await?).await;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(first?.0, before);
+        assert_eq!(second?.0, after);
+        assert_eq!(cached?, after);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_incremental_update_falls_back_to_full_fetch() -> Result<(), GetMirrorStatusError>
+    {
+        let full = test_utils::status();
+        let url = serve(vec![
+            response("404 Not Found", &[], b""),
+            json_response(&serde_json::to_vec(&full)?),
+        ])
+        .await
+        .join("incremental-fallback/json/")
+        .unwrap();
+        let dir = temp_path("incremental-fallback");
+        serde_json::to_writer(
+            std::fs::File::create(get_cache_path(&url, Some(&dir)).await?)?,
+            &MirrorStatus::default(),
+        )?;
+
+        let result = get_mirror_status(
+            http(5),
+            &CacheOptions {
+                dir: Some(dir.clone()),
+                incremental: true,
+                ..Default::default()
+            },
+            &url,
+        )
+        .await;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(result?.0, full);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_write_failure_is_not_fatal() -> Result<(), GetMirrorStatusError> {
+        let served = test_utils::status();
+        let url = serve_status(&served, "unwritable/json/").await;
+        // A directory in place of the cache file makes writing it fail.
+        let dir = temp_path("unwritable");
+        let cache_path = get_cache_path(&url, Some(&dir)).await?;
+        create_dir_all(&cache_path).await?;
+
+        let cache = CacheOptions {
+            dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let result = get_mirror_status(http(5), &cache, &url).await;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(result?.0, served);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fixture_takes_precedence() -> Result<(), GetMirrorStatusError> {
+        let fixture = test_utils::status();
+        let fixture_path = temp_path("fixture.json");
+        serde_json::to_writer(std::fs::File::create(&fixture_path)?, &fixture)?;
+
+        // The fixture is passed explicitly, as setting the environment would
+        // race with the other tests.
+        let arguments = cli::Arguments::parse_from([NAME, "--no-cache"]);
+        let cache = CacheOptions::with_fixture(&arguments, Some(fixture_path.clone()));
+
+        // Nothing listens on the discard port, so any fetch would fail.
+        let url = Url::parse("http://127.0.0.1:9/fixture/json/").unwrap();
+        let result = get_mirror_status(http(1), &cache, &url).await;
+        std::fs::remove_file(&fixture_path)?;
+        assert_eq!(result?.0, fixture);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_url() -> Result<(), GetMirrorStatusError> {
+        let status = MirrorStatus {
+            urls: vec![test_utils::mirror()],
+            ..test_utils::status()
+        };
+        let path = temp_path("status.json");
+        std::fs::write(&path, serde_json::to_vec(&status)?)?;
+        let url = Url::from_file_path(&path).unwrap();
+        let cache = CacheOptions::default();
+        let result = get_mirror_status(http(1), &cache, &url).await;
+
+        std::fs::write(&path, "{")?;
+        let malformed = get_mirror_status(http(1), &cache, &url).await;
+        std::fs::remove_file(&path)?;
+        let missing = get_mirror_status(http(1), &cache, &url).await;
+
+        assert_eq!(result?.0, status);
+        assert!(
+            matches!(malformed, Err(GetMirrorStatusError::Serde(_))),
+            "{malformed:?}"
+        );
+        assert!(
+            matches!(&missing, Err(GetMirrorStatusError::IO(err)) if err.to_string().contains(path.to_str().unwrap())),
+            "{missing:?}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,731 @@
+use std::{
+    collections::HashSet,
+    fs::{remove_file, rename, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use url::Url;
+
+use crate::{
+    cli::OutputFormat,
+    timestamp::{format_utc, parse_rfc3339},
+    Mirror,
+};
+
+/// Prefix of comment lines in the generated mirrorlist.
+const COMMENT_PREFIX: &str = "## ";
+
+/// First line of the section of a mirrorlist managed by [`merge_mirrorlist`].
+const MANAGED_BEGIN: &str = "## BEGIN reflector-rs managed mirrors";
+
+/// Last line of the section of a mirrorlist managed by [`merge_mirrorlist`].
+const MANAGED_END: &str = "## END reflector-rs managed mirrors";
+
+/// Render `text` as mirrorlist comment lines of at most `width` characters.
+///
+/// Lines are only broken at whitespace, so words longer than the available
+/// width, such as URLs, are kept whole on a line of their own.
+pub(crate) fn wrap_comment(text: &str, width: usize) -> Vec<String> {
+    let available = width.saturating_sub(COMMENT_PREFIX.len());
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > available {
+            lines.push(format!("{COMMENT_PREFIX}{line}"));
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    lines.push(format!("{COMMENT_PREFIX}{line}").trim_end().to_string());
+    lines
+}
+
+/// How a mirrorlist was generated, described in its comment header.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Header {
+    /// The command line the mirrorlist was generated with.
+    pub(crate) command: String,
+    /// When the mirrorlist was generated.
+    pub(crate) when: SystemTime,
+    /// The URL the mirror status was retrieved from.
+    pub(crate) from: Url,
+    /// When the mirror status was retrieved, if known.
+    pub(crate) retrieved: Option<SystemTime>,
+    /// When the Mirror Status API last checked the mirrors, as reported.
+    pub(crate) last_check: String,
+    /// Width at which the header is wrapped.
+    pub(crate) width: usize,
+    /// Path of the repositories relative to each mirror URL.
+    pub(crate) mirror_path: String,
+}
+
+/// Format `age` as hours, minutes and seconds, e.g. `1:02:03`.
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Write `mirrors` as a pacman mirrorlist described by `header`.
+pub(crate) fn write_mirrorlist(
+    mirrors: &[Mirror],
+    header: &Header,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    let fields = [
+        ("With", Some(header.command.clone())),
+        ("When", Some(format_utc(header.when))),
+        ("From", Some(header.from.to_string())),
+        ("Retrieved", header.retrieved.map(format_utc)),
+        (
+            "Cache Age",
+            header.retrieved.map(|retrieved| {
+                format_age(header.when.duration_since(retrieved).unwrap_or_default())
+            }),
+        ),
+        (
+            "Last Check",
+            // Still show an unrecognized timestamp, rather than nothing.
+            (!header.last_check.is_empty()).then(|| {
+                parse_rfc3339(&header.last_check)
+                    .map_or_else(|| header.last_check.clone(), format_utc)
+            }),
+        ),
+    ];
+    let lines = wrap_comment(
+        "Arch Linux mirrorlist generated by reflector-rs",
+        header.width,
+    )
+    .into_iter()
+    .chain(wrap_comment("", header.width))
+    .chain(fields.into_iter().flat_map(|(name, value)| {
+        value.map_or_else(Vec::new, |value| {
+            wrap_comment(&format!("{name}: {value}"), header.width)
+        })
+    }));
+    for line in lines {
+        writeln!(writer, "{line}")?;
+    }
+    writeln!(writer)?;
+    for mirror in mirrors {
+        writeln!(writer, "{}", server_line(mirror, &header.mirror_path))?;
+    }
+    writer.flush()
+}
+
+/// Join `suffix` to the mirror URL `base` with exactly one slash, whether
+/// or not `base` ends or `suffix` starts with one.
+pub(crate) fn join_mirror_path(base: &Url, suffix: &str) -> String {
+    format!(
+        "{}/{}",
+        base.as_str().trim_end_matches('/'),
+        suffix.trim_start_matches('/')
+    )
+}
+
+/// The `Server` line of `mirror` in a pacman mirrorlist.
+fn server_line(mirror: &Mirror, mirror_path: &str) -> String {
+    format!("Server = {}", join_mirror_path(&mirror.url, mirror_path))
+}
+
+/// Write `mirrors` in `format`, with `header` describing a pacman mirrorlist.
+///
+/// JSON output is an array of the mirrors as given by the Mirror Status API,
+/// and raw output lists one mirror URL per line.
+pub(crate) fn write_output(
+    mirrors: &[Mirror],
+    header: &Header,
+    format: OutputFormat,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Pacman => return write_mirrorlist(mirrors, header, writer),
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, mirrors)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Raw => {
+            for mirror in mirrors {
+                writeln!(writer, "{}", mirror.url)?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// The temporary file a mirrorlist is written to before replacing `path`.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    path.with_file_name(temp_name)
+}
+
+/// Check that a mirrorlist can be saved to `path`, by creating and removing
+/// the temporary file [`save_mirrorlist`] would write to.
+///
+/// This lets a run fail before retrieving and rating mirrors, rather than
+/// after.
+pub(crate) fn check_writable(path: &Path) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    File::create(&temp_path)?;
+    remove_file(temp_path)
+}
+
+/// Copy the mode and, if permitted, the ownership of `original` to `file`.
+fn copy_metadata(original: &Path, file: &File) -> io::Result<()> {
+    let metadata = original.metadata()?;
+    file.set_permissions(metadata.permissions())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{fchown, MetadataExt};
+        // Changing the owner requires privileges we may lack, which is fine.
+        let _ = fchown(file, Some(metadata.uid()), Some(metadata.gid()));
+    }
+    Ok(())
+}
+
+/// Replace the file at `path` atomically with what `write` writes.
+///
+/// The content is first written to a temporary file in the same directory
+/// and synced to disk, then renamed to `path`, so that an interrupted write
+/// never leaves a truncated file behind. The mode and, where possible, the
+/// ownership of an existing file are kept.
+fn replace_file(
+    path: &Path,
+    write: impl FnOnce(BufWriter<&File>) -> io::Result<()>,
+) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let result = File::create(&temp_path)
+        .and_then(|file| {
+            write(BufWriter::new(&file))?;
+            if path.exists() {
+                copy_metadata(path, &file)?;
+            }
+            file.sync_all()
+        })
+        .and_then(|()| rename(&temp_path, path));
+    if result.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+    result
+}
+
+/// Save the mirrorlist to `path`, replacing any existing file atomically.
+pub(crate) fn save_mirrorlist(
+    path: &Path,
+    mirrors: &[Mirror],
+    header: &Header,
+    format: OutputFormat,
+) -> io::Result<()> {
+    replace_file(path, |writer| write_output(mirrors, header, format, writer))
+}
+
+/// Append the `Server` lines of `mirrors` to the pacman mirrorlist at
+/// `path`, below a comment dated by `header`.
+///
+/// Lines already in the file are skipped, and nothing is appended if all
+/// are. The file is replaced atomically like by [`save_mirrorlist`].
+pub(crate) fn append_mirrorlist(
+    path: &Path,
+    mirrors: &[Mirror],
+    header: &Header,
+) -> io::Result<()> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let present: HashSet<_> = existing.lines().map(str::trim).collect();
+    let mut lines = Vec::new();
+    for mirror in mirrors {
+        let line = server_line(mirror, &header.mirror_path);
+        if !present.contains(line.as_str()) && !lines.contains(&line) {
+            lines.push(line);
+        }
+    }
+    if lines.is_empty() {
+        return Ok(());
+    }
+    replace_file(path, |mut writer| {
+        writer.write_all(existing.as_bytes())?;
+        if !existing.is_empty() {
+            if !existing.ends_with('\n') {
+                writeln!(writer)?;
+            }
+            writeln!(writer)?;
+        }
+        writeln!(
+            writer,
+            "{COMMENT_PREFIX}Appended by reflector-rs on {}",
+            format_utc(header.when)
+        )?;
+        for line in lines {
+            writeln!(writer, "{line}")?;
+        }
+        writer.flush()
+    })
+}
+
+/// The lines of the mirrorlist `existing` outside the section managed by
+/// reflector-rs, without trailing blank lines.
+///
+/// A mirrorlist without the section that was saved whole by reflector-rs,
+/// as recognized by its header, has no such lines.
+fn manual_lines(existing: &str) -> Vec<&str> {
+    let managed = existing
+        .lines()
+        .any(|line| line.trim_end() == MANAGED_BEGIN);
+    if !managed && existing.starts_with(&format!("{COMMENT_PREFIX}Arch Linux mirrorlist")) {
+        return Vec::new();
+    }
+    let mut inside = false;
+    let mut lines: Vec<_> = existing
+        .lines()
+        .filter(|line| match line.trim_end() {
+            MANAGED_BEGIN => {
+                inside = true;
+                false
+            }
+            MANAGED_END if inside => {
+                inside = false;
+                false
+            }
+            _ => !inside,
+        })
+        .collect();
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    lines
+}
+
+/// Save the mirrorlist to `path`, keeping the lines of the existing file
+/// outside the section managed by reflector-rs and replacing that section
+/// with `mirrors`, below them.
+///
+/// The file is replaced atomically like by [`save_mirrorlist`].
+pub(crate) fn merge_mirrorlist(path: &Path, mirrors: &[Mirror], header: &Header) -> io::Result<()> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let manual = manual_lines(&existing);
+    replace_file(path, |mut writer| {
+        for line in &manual {
+            writeln!(writer, "{line}")?;
+        }
+        if !manual.is_empty() {
+            writeln!(writer)?;
+        }
+        writeln!(writer, "{MANAGED_BEGIN}")?;
+        write_mirrorlist(mirrors, header, &mut writer)?;
+        writeln!(writer, "{MANAGED_END}")?;
+        writer.flush()
+    })
+}
+
+/// The URLs listed by `mirrors` once written in `format`: their `Server`
+/// lines in a pacman mirrorlist, below `mirror_path`, and their own URLs
+/// otherwise.
+fn listed_urls(mirrors: &[Mirror], format: OutputFormat, mirror_path: &str) -> HashSet<String> {
+    mirrors
+        .iter()
+        .map(|mirror| match format {
+            OutputFormat::Pacman => join_mirror_path(&mirror.url, mirror_path),
+            OutputFormat::Json | OutputFormat::Raw => mirror.url.to_string(),
+        })
+        .collect()
+}
+
+/// Parse the URLs listed by `contents`, written in `format`, as given by
+/// [`listed_urls`].
+fn parse_urls(contents: &str, format: OutputFormat) -> io::Result<HashSet<String>> {
+    Ok(match format {
+        OutputFormat::Pacman => contents
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("Server"))
+            .filter_map(|line| line.trim_start().strip_prefix('='))
+            .map(|url| url.trim().to_string())
+            .collect(),
+        OutputFormat::Json => serde_json::from_str::<Vec<Mirror>>(contents)?
+            .into_iter()
+            .map(|mirror| mirror.url.to_string())
+            .collect(),
+        OutputFormat::Raw => contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect(),
+    })
+}
+
+/// Save the mirrorlist to `path` like [`save_mirrorlist`], or like
+/// [`append_mirrorlist`] with `append`, then re-read it and check that it
+/// lists exactly the mirrors it should.
+///
+/// If it does not, the previous file is restored, or the new one removed if
+/// there was none, and an [`io::ErrorKind::InvalidData`] error is returned.
+pub(crate) fn save_verified(
+    path: &Path,
+    mirrors: &[Mirror],
+    header: &Header,
+    format: OutputFormat,
+    append: bool,
+) -> io::Result<()> {
+    let previous = match std::fs::read(path) {
+        Ok(previous) => Some(previous),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    let format = if append { OutputFormat::Pacman } else { format };
+    let mut expected = listed_urls(mirrors, format, &header.mirror_path);
+    if append {
+        append_mirrorlist(path, mirrors, header)?;
+        if let Some(previous) = &previous {
+            expected.extend(parse_urls(&String::from_utf8_lossy(previous), format)?);
+        }
+    } else {
+        save_mirrorlist(path, mirrors, header, format)?;
+    }
+    let saved = std::fs::read(path)?;
+    let saved = String::from_utf8(saved)
+        .ok()
+        .and_then(|saved| parse_urls(&saved, format).ok());
+    if saved.as_ref() == Some(&expected) {
+        return Ok(());
+    }
+    match previous {
+        Some(previous) => replace_file(path, |mut writer| {
+            writer.write_all(&previous)?;
+            writer.flush()
+        })?,
+        None => remove_file(path)?,
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "the mirrorlist saved to {} does not list the selected mirrors, \
+             restored the previous file",
+            path.display()
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::mirror, timestamp::parse_rfc3339};
+
+    fn header() -> Header {
+        Header {
+            command: "reflector-rs --country de --save /etc/pacman.d/mirrorlist".to_string(),
+            when: parse_rfc3339("2023-09-20T10:30:00Z").unwrap(),
+            from: Url::parse("https://archlinux.org/mirrors/status/json/").unwrap(),
+            retrieved: parse_rfc3339("2023-09-20T10:29:00Z"),
+            last_check: "2023-09-20T10:08:33.164Z".to_string(),
+            width: 80,
+            mirror_path: "$repo/os/$arch".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_write_mirrorlist() {
+        let mut output = Vec::new();
+        write_mirrorlist(&[mirror()], &header(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+## Arch Linux mirrorlist generated by reflector-rs
+##
+## With: reflector-rs --country de --save /etc/pacman.d/mirrorlist
+## When: 2023-09-20 10:30:00 UTC
+## From: https://archlinux.org/mirrors/status/json/
+## Retrieved: 2023-09-20 10:29:00 UTC
+## Cache Age: 0:01:00
+## Last Check: 2023-09-20 10:08:33 UTC
+
+Server = https://mirror.example.com/archlinux/$repo/os/$arch
+"
+        );
+    }
+
+    #[test]
+    fn test_unparsed_last_check() {
+        let header = Header {
+            retrieved: None,
+            last_check: "yesterday".to_string(),
+            ..header()
+        };
+        let mut output = Vec::new();
+        write_mirrorlist(&[], &header, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\n## Last Check: yesterday\n"), "{output}");
+        assert!(!output.contains("Cache Age"), "{output}");
+    }
+
+    #[test]
+    fn test_mirror_path() {
+        let mirrors = [
+            mirror(),
+            Mirror {
+                url: Url::parse("https://mirror.example.org/arch").unwrap(),
+                ..mirror()
+            },
+        ];
+        let header = Header {
+            mirror_path: "$repo/os/aarch64".to_string(),
+            ..header()
+        };
+        let mut output = Vec::new();
+        write_mirrorlist(&mirrors, &header, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let servers: Vec<_> = output
+            .lines()
+            .filter(|line| line.starts_with("Server"))
+            .collect();
+        assert_eq!(
+            servers,
+            [
+                "Server = https://mirror.example.com/archlinux/$repo/os/aarch64",
+                "Server = https://mirror.example.org/arch/$repo/os/aarch64",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_mirror_path() {
+        let expected = "https://mirror.example.com/archlinux/$repo/os/$arch";
+        for base in [
+            "https://mirror.example.com/archlinux",
+            "https://mirror.example.com/archlinux/",
+        ] {
+            let base = Url::parse(base).unwrap();
+            for suffix in ["$repo/os/$arch", "/$repo/os/$arch"] {
+                assert_eq!(join_mirror_path(&base, suffix), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_save_mirrorlist() {
+        let path =
+            std::env::temp_dir().join(format!("reflector-rs-{}-mirrorlist", std::process::id()));
+        std::fs::write(&path, "old").unwrap();
+        save_mirrorlist(&path, &[mirror()], &header(), OutputFormat::Pacman).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(saved.ends_with("Server = https://mirror.example.com/archlinux/$repo/os/$arch\n"));
+        assert!(saved.starts_with(COMMENT_PREFIX));
+    }
+
+    #[test]
+    fn test_failed_save_keeps_original() {
+        let path =
+            std::env::temp_dir().join(format!("reflector-rs-{}-failed-save", std::process::id()));
+        std::fs::write(&path, "old").unwrap();
+        // A directory in the way of the temporary file makes the write fail.
+        std::fs::create_dir(temp_path(&path)).unwrap();
+        let saved = save_mirrorlist(&path, &[mirror()], &header(), OutputFormat::Pacman);
+        let writable = check_writable(&path);
+        std::fs::remove_dir(temp_path(&path)).unwrap();
+        let original = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(saved.is_err());
+        assert!(writable.is_err());
+        assert_eq!(original, "old");
+        assert!(check_writable(&path.join("mirrorlist")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("reflector-rs-{}-save-mode", std::process::id()));
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        check_writable(&path).unwrap();
+        save_mirrorlist(&path, &[mirror()], &header(), OutputFormat::Pacman).unwrap();
+        let mode = path.metadata().unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn test_append_mirrorlist() {
+        let path = std::env::temp_dir().join(format!("reflector-rs-{}-append", std::process::id()));
+        let old = "## My mirrors\nServer = https://mirror.example.com/archlinux/$repo/os/$arch\n";
+        std::fs::write(&path, old).unwrap();
+        let mirrors = [
+            mirror(),
+            Mirror {
+                url: Url::parse("https://mirror.example.org/arch/").unwrap(),
+                ..mirror()
+            },
+        ];
+        append_mirrorlist(&path, &mirrors, &header()).unwrap();
+        // Appending the same mirrors again changes nothing.
+        append_mirrorlist(&path, &mirrors, &header()).unwrap();
+        let appended = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            appended,
+            format!(
+                "{old}
+## Appended by reflector-rs on 2023-09-20 10:30:00 UTC
+Server = https://mirror.example.org/arch/$repo/os/$arch
+"
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_mirrorlist() {
+        let path = std::env::temp_dir().join(format!("reflector-rs-{}-merge", std::process::id()));
+        let manual = "## LAN mirror\nServer = http://192.168.1.10/archlinux/$repo/os/$arch\n";
+        std::fs::write(&path, manual).unwrap();
+        let generated = |url| {
+            let mirror = Mirror {
+                url: Url::parse(url).unwrap(),
+                ..mirror()
+            };
+            let mut output = Vec::new();
+            write_mirrorlist(std::slice::from_ref(&mirror), &header(), &mut output).unwrap();
+            (mirror, String::from_utf8(output).unwrap())
+        };
+        let (first, _) = generated("https://mirror.example.org/arch/");
+        merge_mirrorlist(&path, &[first], &header()).unwrap();
+        let (second, second_list) = generated("https://mirror.example.net/arch/");
+        merge_mirrorlist(&path, &[second], &header()).unwrap();
+        let merged = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            merged,
+            format!("{manual}\n{MANAGED_BEGIN}\n{second_list}{MANAGED_END}\n")
+        );
+    }
+
+    #[test]
+    fn test_manual_lines() {
+        let existing = format!(
+            "Server = http://lan/$repo/os/$arch\n\n{MANAGED_BEGIN}\nServer = https://old/\n\
+             {MANAGED_END}\n## Below\n\n"
+        );
+        assert_eq!(
+            manual_lines(&existing),
+            ["Server = http://lan/$repo/os/$arch", "", "## Below"]
+        );
+        let mut saved = Vec::new();
+        write_mirrorlist(&[mirror()], &header(), &mut saved).unwrap();
+        assert!(manual_lines(&String::from_utf8(saved).unwrap()).is_empty());
+        assert!(manual_lines("").is_empty());
+    }
+
+    #[test]
+    fn test_save_verified() {
+        let path = std::env::temp_dir().join(format!("reflector-rs-{}-verify", std::process::id()));
+        let mirrors = [
+            mirror(),
+            Mirror {
+                url: Url::parse("https://mirror.example.org/arch/").unwrap(),
+                ..mirror()
+            },
+        ];
+        let mut results = Vec::new();
+        for format in [OutputFormat::Pacman, OutputFormat::Json, OutputFormat::Raw] {
+            results.push(save_verified(&path, &mirrors, &header(), format, false));
+        }
+        std::fs::write(
+            &path,
+            "Server = https://mirror.example.net/$repo/os/$arch\n",
+        )
+        .unwrap();
+        results.push(save_verified(
+            &path,
+            &mirrors[..1],
+            &header(),
+            OutputFormat::Json,
+            true,
+        ));
+        let appended = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for result in results {
+            result.unwrap();
+        }
+        assert!(appended.starts_with("Server = https://mirror.example.net/"));
+        assert!(
+            appended.ends_with("Server = https://mirror.example.com/archlinux/$repo/os/$arch\n")
+        );
+    }
+
+    #[test]
+    fn test_failed_verification_restores_previous() {
+        let path = std::env::temp_dir().join(format!(
+            "reflector-rs-{}-verify-restore",
+            std::process::id()
+        ));
+        // A line break in the mirror path splits the Server lines as written.
+        let header = Header {
+            mirror_path: "$repo/os/$arch\nServer = https://example.com".to_string(),
+            ..header()
+        };
+        let failed_new = save_verified(&path, &[mirror()], &header, OutputFormat::Pacman, false);
+        let created = path.exists();
+        std::fs::write(&path, "old").unwrap();
+        let failed = save_verified(&path, &[mirror()], &header, OutputFormat::Pacman, false);
+        let restored = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(failed_new.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(!created);
+        assert_eq!(failed.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(restored, "old");
+    }
+
+    #[test]
+    fn test_wrap_comment() {
+        let text = "With: reflector-rs --country France,Germany --protocols https \
+            --latest 20 --sort rate --save /etc/pacman.d/mirrorlist";
+        let lines = wrap_comment(text, 40);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|line| line.starts_with("## ")));
+        assert!(lines.iter().all(|line| line.len() <= 40), "{lines:#?}");
+        assert_eq!(lines.join(" ").replace("## ", ""), text);
+
+        let url = "https://mirror.example.com/a/very/long/path/that/cannot/be/wrapped/";
+        assert_eq!(
+            wrap_comment(&format!("Source: {url}"), 40),
+            ["## Source:".to_string(), format!("## {url}")]
+        );
+    }
+
+    #[test]
+    fn test_write_output() {
+        let mirrors = vec![
+            mirror(),
+            Mirror {
+                url: Url::parse("rsync://mirror.example.org/archlinux/").unwrap(),
+                protocol: "rsync".to_string(),
+                score: Some(1.5),
+                ..mirror()
+            },
+        ];
+        let output = |format| {
+            let mut output = Vec::new();
+            write_output(&mirrors, &header(), format, &mut output).unwrap();
+            output
+        };
+        let json: Vec<Mirror> = serde_json::from_slice(&output(OutputFormat::Json)).unwrap();
+        assert_eq!(json, mirrors);
+        assert_eq!(
+            String::from_utf8(output(OutputFormat::Raw)).unwrap(),
+            "https://mirror.example.com/archlinux/\nrsync://mirror.example.org/archlinux/\n"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Options of the library API, independent of the command line.
+
+use clap::Parser;
+
+use crate::{
+    cli::{Arguments, CountryOrder, Filters, Protocol, SortType},
+    rate::ProbeOptions,
+    HttpOptions, NAME,
+};
+
+/// How [`reflect`](crate::reflect) filters, rates and sorts mirrors.
+///
+/// Build them with [`Options::builder`], or convert the command line
+/// [`Arguments`] into them.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub(crate) filters: Filters,
+    pub(crate) sort: Option<SortType>,
+    pub(crate) country_order_by: CountryOrder,
+    /// Order of preference of the protocols, to break ties when sorting.
+    pub(crate) protocol_sort_weights: Vec<Protocol>,
+    pub(crate) http: HttpOptions,
+    /// Number of mirrors rated at a time.
+    pub(crate) threads: usize,
+    pub(crate) probe: ProbeOptions,
+}
+
+impl Options {
+    /// A builder starting from the defaults of the command line.
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder {
+            options: Options::from(&Arguments::parse_from([NAME])),
+        }
+    }
+
+    /// Whether the mirrors must be rated, for "fastest" or sorting by rate,
+    /// including by country ordered by rate.
+    pub(crate) fn needs_rating(&self) -> bool {
+        let orders_by_rate = self.country_order_by != CountryOrder::Name;
+        self.filters.fastest.is_some()
+            || match self.sort {
+                Some(SortType::Rate) => true,
+                Some(SortType::Country) => orders_by_rate,
+                _ => false,
+            }
+    }
+}
+
+impl From<&Arguments> for Options {
+    fn from(arguments: &Arguments) -> Self {
+        Options {
+            filters: arguments.filters.clone(),
+            sort: arguments.sort.clone(),
+            country_order_by: arguments.country_order_by,
+            protocol_sort_weights: arguments.protocol_sort_weights.clone(),
+            http: HttpOptions::from(arguments),
+            threads: arguments.threads.unwrap_or(1) as usize,
+            probe: ProbeOptions::from(arguments),
+        }
+    }
+}
+
+/// Builds [`Options`], each method setting the command line option of the
+/// same name.
+#[derive(Clone, Debug)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    /// Only keep mirrors that have synchronized in the last `hours`.
+    pub fn age(mut self, hours: f64) -> Self {
+        self.options.filters.age = Some(hours);
+        self
+    }
+
+    /// Only keep mirrors in these countries, given by name or code.
+    pub fn country<S: Into<String>>(mut self, countries: impl IntoIterator<Item = S>) -> Self {
+        self.options.filters.country = Some(countries.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only keep mirrors served over one of `protocols`.
+    pub fn protocols(mut self, protocols: impl IntoIterator<Item = Protocol>) -> Self {
+        self.options.filters.protocols = Some(protocols.into_iter().collect());
+        self
+    }
+
+    /// Only keep mirrors matching the regular expression `pattern`.
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.options.filters.include = Some(pattern.into());
+        self
+    }
+
+    /// Drop mirrors matching the regular expression `pattern`.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.options.filters.exclude = Some(pattern.into());
+        self
+    }
+
+    /// Only keep the `n` most recently synchronized mirrors.
+    pub fn latest(mut self, n: usize) -> Self {
+        self.options.filters.latest = Some(n);
+        self
+    }
+
+    /// Only keep the `n` mirrors with the best score.
+    pub fn score(mut self, n: usize) -> Self {
+        self.options.filters.score = Some(n);
+        self
+    }
+
+    /// Only keep the `n` fastest mirrors, rating them first.
+    pub fn fastest(mut self, n: usize) -> Self {
+        self.options.filters.fastest = Some(n);
+        self
+    }
+
+    /// Keep at least `k` https mirrors among the fastest.
+    pub fn fastest_min_https(mut self, k: usize) -> Self {
+        self.options.filters.fastest_min_https = Some(k);
+        self
+    }
+
+    /// Keep at most `n` mirrors in the end.
+    pub fn number(mut self, n: usize) -> Self {
+        self.options.filters.number = Some(n);
+        self
+    }
+
+    /// Only keep mirrors with at least this completion percentage.
+    pub fn completion_percent(mut self, percent: f64) -> Self {
+        self.options.filters.completion_percent = percent;
+        self
+    }
+
+    /// Only keep mirrors hosting ISOs.
+    pub fn isos(mut self, isos: bool) -> Self {
+        self.options.filters.isos = isos;
+        self
+    }
+
+    /// Only keep mirrors supporting IPv4.
+    pub fn ipv4(mut self, ipv4: bool) -> Self {
+        self.options.filters.ipv4 = ipv4;
+        self
+    }
+
+    /// Only keep mirrors supporting IPv6.
+    pub fn ipv6(mut self, ipv6: bool) -> Self {
+        self.options.filters.ipv6 = ipv6;
+        self
+    }
+
+    /// Sort the mirrors by `sort`.
+    pub fn sort(mut self, sort: SortType) -> Self {
+        self.options.sort = Some(sort);
+        self
+    }
+
+    /// Order the countries when sorting by country by `order`.
+    pub fn country_order_by(mut self, order: CountryOrder) -> Self {
+        self.options.country_order_by = order;
+        self
+    }
+
+    /// Rate up to `threads` mirrors at a time.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.options.threads = threads;
+        self
+    }
+
+    /// The options set so far.
+    pub fn build(self) -> Options {
+        self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let options = Options::builder()
+            .age(24.0)
+            .country(["DE", "France"])
+            .protocols([Protocol::Https])
+            .fastest(5)
+            .fastest_min_https(2)
+            .sort(SortType::Score)
+            .build();
+        assert_eq!(options.filters.age, Some(24.0));
+        assert_eq!(
+            options.filters.country,
+            Some(vec!["DE".to_string(), "France".to_string()])
+        );
+        assert!(matches!(
+            options.filters.protocols.as_deref(),
+            Some([Protocol::Https])
+        ));
+        assert_eq!(options.filters.fastest, Some(5));
+        assert_eq!(options.filters.fastest_min_https, Some(2));
+        assert_eq!(options.filters.completion_percent, 100.0);
+        assert_eq!(options.threads, 1);
+        assert!(options.needs_rating());
+        assert!(!Options::builder().build().needs_rating());
+        assert!(Options::builder()
+            .sort(SortType::Rate)
+            .build()
+            .needs_rating());
+        let by_country = Options::builder().sort(SortType::Country);
+        assert!(!by_country.clone().build().needs_rating());
+        assert!(by_country
+            .country_order_by(CountryOrder::Rate)
+            .build()
+            .needs_rating());
+    }
+
+    #[test]
+    fn test_options_from_arguments() {
+        let arguments = Arguments::parse_from([
+            "reflector-rs",
+            "--latest",
+            "10",
+            "--sort",
+            "age",
+            "--threads",
+            "4",
+            "--rate-samples",
+            "3",
+        ]);
+        let options = Options::from(&arguments);
+        assert_eq!(options.filters.latest, Some(10));
+        assert!(matches!(options.sort, Some(SortType::Age)));
+        assert_eq!(options.threads, 4);
+        assert_eq!(options.probe.samples, 3);
+        assert_eq!(options.http, HttpOptions::from(&arguments));
+    }
+}
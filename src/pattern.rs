@@ -0,0 +1,543 @@
+//! A small regular expression engine for `--include` and `--exclude`.
+//!
+//! Patterns are compiled to a program run by a Pike VM, which tracks every
+//! possible match at once instead of backtracking, so matching takes time
+//! linear in the length of the text whatever the pattern.
+//!
+//! Supported syntax: literals, `.`, `^`, `$`, groups `(...)` and `(?:...)`,
+//! alternation `|`, the quantifiers `*`, `+`, `?`, `{n}`, `{n,}` and `{n,m}`
+//! (optionally followed by `?`), bracket classes such as `[a-z_]` and `[^/]`,
+//! and the escapes `\d`, `\w`, `\s`, their negations, `\n`, `\t` and `\r`.
+//! Any other escaped punctuation matches literally.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("invalid regular expression {pattern:?}: {reason}")]
+pub struct RegexError {
+    pattern: String,
+    reason: &'static str,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Perl {
+    Digit,
+    Word,
+    Space,
+}
+
+impl Perl {
+    fn matches(self, c: char) -> bool {
+        match self {
+            Perl::Digit => c.is_ascii_digit(),
+            Perl::Word => c.is_alphanumeric() || c == '_',
+            Perl::Space => c.is_whitespace(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum ClassItem {
+    Range(char, char),
+    Perl(Perl, bool),
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    Alternation(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+impl Node {
+    /// Whether this node, which must consume exactly one character, matches `c`.
+    fn matches_char(&self, c: char) -> bool {
+        match self {
+            Node::Char(expected) => *expected == c,
+            Node::Any => c != '\n',
+            Node::Class(items, negated) => {
+                items.iter().any(|item| match *item {
+                    ClassItem::Range(low, high) => (low..=high).contains(&c),
+                    ClassItem::Perl(perl, negated) => perl.matches(c) != negated,
+                }) != *negated
+            }
+            _ => false,
+        }
+    }
+}
+
+/// An instruction of a compiled regular expression.
+#[derive(Debug)]
+enum Inst {
+    /// Consume one character matching the node, which is a [`Node::Char`],
+    /// [`Node::Any`] or [`Node::Class`].
+    Consume(Node),
+    Start,
+    End,
+    /// Continue at both instructions.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+/// Upper bound on the size of a compiled program, mostly reached by nesting
+/// counted repetitions.
+const MAX_PROGRAM_LEN: usize = 100_000;
+
+/// Compiles parsed nodes to a program, returning `None` once it grows past
+/// [`MAX_PROGRAM_LEN`].
+#[derive(Default)]
+struct Compiler {
+    program: Vec<Inst>,
+}
+
+impl Compiler {
+    fn emit(&mut self, inst: Inst) -> Option<usize> {
+        if self.program.len() >= MAX_PROGRAM_LEN {
+            return None;
+        }
+        self.program.push(inst);
+        Some(self.program.len() - 1)
+    }
+
+    /// Emit a placeholder to be patched by [`Compiler::split_to_here`].
+    fn split(&mut self) -> Option<usize> {
+        self.emit(Inst::Split(0, 0))
+    }
+
+    /// Patch the split at `at` to continue either after it or here.
+    fn split_to_here(&mut self, at: usize) {
+        self.program[at] = Inst::Split(at + 1, self.program.len());
+    }
+
+    fn compile_sequence(&mut self, nodes: &[Node]) -> Option<()> {
+        nodes.iter().try_for_each(|node| self.compile(node))
+    }
+
+    fn compile(&mut self, node: &Node) -> Option<()> {
+        match node {
+            Node::Start => {
+                self.emit(Inst::Start)?;
+            }
+            Node::End => {
+                self.emit(Inst::End)?;
+            }
+            Node::Alternation(branches) => {
+                let (last, branches) = branches.split_last()?;
+                let mut jumps = Vec::new();
+                for branch in branches {
+                    let split = self.split()?;
+                    self.compile_sequence(branch)?;
+                    jumps.push(self.emit(Inst::Jump(0))?);
+                    self.split_to_here(split);
+                }
+                self.compile_sequence(last)?;
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat(node, min, max) => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.split()?;
+                        self.compile(node)?;
+                        self.emit(Inst::Jump(split))?;
+                        self.split_to_here(split);
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.split()?);
+                            self.compile(node)?;
+                        }
+                        let end = self.program.len();
+                        for split in splits {
+                            self.program[split] = Inst::Split(split + 1, end);
+                        }
+                    }
+                }
+            }
+            node => {
+                self.emit(Inst::Consume(node.clone()))?;
+            }
+        }
+        Some(())
+    }
+}
+
+/// The set of instructions waiting to consume the character at one position.
+struct Threads {
+    pcs: Vec<usize>,
+    /// Instructions already followed at this position.
+    seen: Vec<bool>,
+    visited: Vec<usize>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Threads {
+            pcs: Vec::with_capacity(len),
+            seen: vec![false; len],
+            visited: Vec::with_capacity(len),
+        }
+    }
+
+    fn clear(&mut self) {
+        for &pc in &self.visited {
+            self.seen[pc] = false;
+        }
+        self.visited.clear();
+        self.pcs.clear();
+    }
+
+    /// Follow the instructions from `pc` at `pos` in a text of `len`
+    /// characters, adding those that consume a character. Returns whether a
+    /// match was reached.
+    fn add(&mut self, program: &[Inst], pc: usize, pos: usize, len: usize) -> bool {
+        let mut stack = vec![pc];
+        let mut matched = false;
+        while let Some(pc) = stack.pop() {
+            if self.seen[pc] {
+                continue;
+            }
+            self.seen[pc] = true;
+            self.visited.push(pc);
+            match program[pc] {
+                Inst::Consume(_) => self.pcs.push(pc),
+                Inst::Jump(to) => stack.push(to),
+                Inst::Split(first, second) => stack.extend([second, first]),
+                Inst::Start if pos == 0 => stack.push(pc + 1),
+                Inst::End if pos == len => stack.push(pc + 1),
+                Inst::Start | Inst::End => {}
+                Inst::Match => matched = true,
+            }
+        }
+        matched
+    }
+}
+
+/// A compiled regular expression.
+#[derive(Debug)]
+pub(crate) struct Regex {
+    program: Vec<Inst>,
+}
+
+struct Parser<'a> {
+    pattern: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &'static str) -> RegexError {
+        RegexError {
+            pattern: self.pattern.to_string(),
+            reason,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn parse_alternation(&mut self) -> Result<Node, RegexError> {
+        let mut branches = vec![self.parse_sequence()?];
+        while self.eat('|') {
+            branches.push(self.parse_sequence()?);
+        }
+        Ok(Node::Alternation(branches))
+    }
+
+    fn parse_sequence(&mut self) -> Result<Vec<Node>, RegexError> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_quantifier(atom)?);
+        }
+        Ok(nodes)
+    }
+
+    fn parse_number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    fn parse_quantifier(&mut self, atom: Node) -> Result<Node, RegexError> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = self
+                    .parse_number()
+                    .ok_or_else(|| self.error("invalid repetition"))?;
+                let max = if self.eat(',') {
+                    self.parse_number()
+                } else {
+                    Some(min)
+                };
+                if self.peek() != Some('}') || max.is_some_and(|max| max < min) {
+                    return Err(self.error("invalid repetition"));
+                }
+                (min, max)
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        // Laziness does not affect whether a pattern matches.
+        self.eat('?');
+        if matches!(atom, Node::Start | Node::End) {
+            return Err(self.error("nothing to repeat"));
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    fn parse_escape(&mut self) -> Result<Result<char, (Perl, bool)>, RegexError> {
+        let c = self
+            .peek()
+            .ok_or_else(|| self.error("trailing backslash"))?;
+        self.pos += 1;
+        Ok(match c {
+            'd' => Err((Perl::Digit, false)),
+            'D' => Err((Perl::Digit, true)),
+            'w' => Err((Perl::Word, false)),
+            'W' => Err((Perl::Word, true)),
+            's' => Err((Perl::Space, false)),
+            'S' => Err((Perl::Space, true)),
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            c if c.is_ascii_alphanumeric() => return Err(self.error("unsupported escape")),
+            c => Ok(c),
+        })
+    }
+
+    fn parse_class(&mut self) -> Result<Node, RegexError> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated character class"))?;
+            self.pos += 1;
+            let low = match c {
+                ']' if !first => return Ok(Node::Class(items, negated)),
+                '\\' => match self.parse_escape()? {
+                    Ok(c) => c,
+                    Err((perl, negated)) => {
+                        items.push(ClassItem::Perl(perl, negated));
+                        first = false;
+                        continue;
+                    }
+                },
+                c => c,
+            };
+            first = false;
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                let high = match self.peek() {
+                    Some('\\') => {
+                        self.pos += 1;
+                        self.parse_escape()?
+                            .map_err(|_| self.error("invalid class range"))?
+                    }
+                    Some(c) => {
+                        self.pos += 1;
+                        c
+                    }
+                    None => return Err(self.error("unterminated character class")),
+                };
+                if high < low {
+                    return Err(self.error("invalid class range"));
+                }
+                items.push(ClassItem::Range(low, high));
+            } else {
+                items.push(ClassItem::Range(low, low));
+            }
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, RegexError> {
+        let c = self.peek().ok_or_else(|| self.error("unexpected end"))?;
+        self.pos += 1;
+        Ok(match c {
+            '(' => {
+                if self.eat('?') && !self.eat(':') {
+                    return Err(self.error("unsupported group flags"));
+                }
+                let group = self.parse_alternation()?;
+                if !self.eat(')') {
+                    return Err(self.error("unclosed group"));
+                }
+                group
+            }
+            '[' => self.parse_class()?,
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '*' | '+' | '?' | '{' => return Err(self.error("nothing to repeat")),
+            '\\' => match self.parse_escape()? {
+                Ok(c) => Node::Char(c),
+                Err((perl, negated)) => Node::Class(vec![ClassItem::Perl(perl, negated)], false),
+            },
+            c => Node::Char(c),
+        })
+    }
+}
+
+impl Regex {
+    /// Compile `pattern`, failing with a [`RegexError`] if it is invalid or
+    /// uses unsupported syntax.
+    pub(crate) fn new(pattern: &str) -> Result<Regex, RegexError> {
+        let mut parser = Parser {
+            pattern,
+            chars: pattern.chars().collect(),
+            pos: 0,
+        };
+        let node = parser.parse_alternation()?;
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("unmatched closing parenthesis"));
+        }
+        let mut compiler = Compiler::default();
+        compiler
+            .compile(&node)
+            .and_then(|()| compiler.emit(Inst::Match))
+            .ok_or_else(|| parser.error("pattern too large"))?;
+        Ok(Regex {
+            program: compiler.program,
+        })
+    }
+
+    /// Whether the expression matches anywhere in `text`.
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        for pos in 0..=chars.len() {
+            // Start a new attempt at every position to match anywhere.
+            if current.add(&self.program, 0, pos, chars.len()) {
+                return true;
+            }
+            let Some(&c) = chars.get(pos) else {
+                break;
+            };
+            for &pc in &current.pcs {
+                if let Inst::Consume(node) = &self.program[pc] {
+                    if node.matches_char(c) && next.add(&self.program, pc + 1, pos + 1, chars.len())
+                    {
+                        return true;
+                    }
+                }
+            }
+            current.clear();
+            std::mem::swap(&mut current, &mut next);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, text: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(text)
+    }
+
+    #[test]
+    fn test_matching() {
+        assert!(is_match("^rsync", "rsync://mirror.example.com/"));
+        assert!(!is_match("^rsync", "https://rsync.example.com/"));
+        assert!(is_match(r"\.de/", "https://mirror.example.de/archlinux/"));
+        assert!(!is_match(r"\.de/", "https://mirror.example.dev/archlinux/"));
+        assert!(is_match("^(https?|ftp)://", "http://mirror.example.com/"));
+        assert!(is_match(
+            "^https?://[^/]+/arch(linux)?/$",
+            "https://a.b/arch/"
+        ));
+        assert!(!is_match(
+            "^https?://[^/]+/arch(linux)?/$",
+            "https://a.b/c/arch/"
+        ));
+        assert!(is_match(
+            r"mirror\d{2,3}\.",
+            "https://mirror042.example.com/"
+        ));
+        assert!(!is_match(
+            r"mirror\d{2,3}\.",
+            "https://mirror4.example.com/"
+        ));
+        assert!(is_match("(a*)*b", "aaab"));
+        assert!(!is_match("(a*)*b", "aaa"));
+        assert!(is_match("", "anything"));
+        assert!(is_match("[-a]x$", "-x"));
+        assert!(is_match("ü", "Türkiye"));
+    }
+
+    #[test]
+    fn test_nested_quantifiers_are_linear() {
+        let start = std::time::Instant::now();
+        assert!(!is_match("(a*)*b", &"a".repeat(30)));
+        assert!(!is_match("^(.*)*x$", &"y".repeat(50)));
+        assert!(!is_match(
+            r"^https?://([a-z0-9-]*\.?)*\.de/",
+            "https://mirror-1.example-university.computing.example.org/"
+        ));
+        assert!(is_match(
+            r"^https?://([a-z0-9-]*\.?)*\.de/",
+            "https://mirror-1.example-university.computing.example.de/"
+        ));
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        for pattern in [
+            "(",
+            ")",
+            "[a-",
+            "[z-a]",
+            "*a",
+            "a{2,1}",
+            "a**",
+            r"\",
+            r"\b",
+            "(a{999}){999}",
+        ] {
+            assert!(Regex::new(pattern).is_err(), "{pattern}");
+        }
+        let err = Regex::new("(https").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid regular expression "(https": unclosed group"#
+        );
+    }
+}
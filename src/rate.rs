@@ -0,0 +1,734 @@
+//! Rating of mirrors by download rate, for `--fastest` and `--sort rate`.
+
+use std::{
+    collections::HashMap,
+    error::Error as _,
+    future::Future,
+    io::{stderr, IsTerminal, Write},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use log::{debug, info, warn};
+use reqwest::{
+    header::{CONTENT_LENGTH, RANGE},
+    Client, Response,
+};
+use thiserror::Error;
+use tokio::{sync::Semaphore, task::JoinSet};
+use url::Url;
+
+use crate::{client_builder, Arguments, ByteCounter, HttpOptions, Mirror};
+
+/// Architecture of the repository database downloaded to rate a mirror.
+pub(crate) const PROBE_ARCH: &str = "x86_64";
+
+/// Path of the database of `repo` relative to a mirror URL, e.g.
+/// `core/os/x86_64/core.db`.
+fn probe_path(repo: &str) -> String {
+    format!("{repo}/os/{PROBE_ARCH}/{repo}.db")
+}
+
+/// URL of the database of `repo` downloaded to rate the mirror at `url`,
+/// at [`probe_path`] relative to it.
+///
+/// Returns `None` unless the resulting URL lies below `url`, which a `repo`
+/// containing `/`, `..`, `?` or `#` would escape.
+pub(crate) fn probe_url(url: &Url, repo: &str) -> Option<Url> {
+    let mut url = url.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    let probe = url.join(&probe_path(repo)).ok()?;
+    let below = probe.path().strip_prefix(url.path())?;
+    let valid = below.split('/').count() == 4 && probe.query() == url.query();
+    (valid && probe.fragment().is_none()).then_some(probe)
+}
+
+/// Read the body of `response`, stopping after `limit` bytes if given, and
+/// return the number of bytes read, counting all of them in `downloaded`.
+///
+/// Dropping the response afterwards aborts the rest of the transfer.
+pub(crate) async fn read_body(
+    mut response: Response,
+    limit: Option<u64>,
+    downloaded: &ByteCounter,
+) -> reqwest::Result<u64> {
+    let mut read = 0;
+    while let Some(chunk) = response.chunk().await? {
+        read += chunk.len() as u64;
+        downloaded.add(chunk.len() as u64);
+        if let Some(limit) = limit.filter(|&limit| read >= limit) {
+            return Ok(limit);
+        }
+    }
+    Ok(read)
+}
+
+/// Why a mirror could not be rated.
+#[derive(Debug, Error)]
+pub(crate) enum ProbeError {
+    #[error("cannot be downloaded over HTTP")]
+    UnsupportedProtocol,
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("{0}")]
+    Download(reqwest::Error),
+}
+
+impl From<reqwest::Error> for ProbeError {
+    fn from(err: reqwest::Error) -> Self {
+        match tls_cause(&err) {
+            Some(cause) => ProbeError::Tls(cause.to_string()),
+            None => ProbeError::Download(err),
+        }
+    }
+}
+
+/// The TLS error that made `err` fail to connect, such as an invalid
+/// certificate, as opposed to an unreachable host or a refused connection.
+pub(crate) fn tls_cause(err: &reqwest::Error) -> Option<&native_tls::Error> {
+    if !err.is_connect() {
+        return None;
+    }
+    std::iter::successors(err.source(), |&cause| cause.source())
+        .find_map(|cause| cause.downcast_ref::<native_tls::Error>())
+}
+
+/// Sizes of the probed files learnt from `HEAD` requests, by their path
+/// relative to the mirror URL, as every mirror serves the same files.
+pub(crate) type SizeCache = Mutex<HashMap<String, u64>>;
+
+/// The size of the file at `url`, whose path relative to its mirror is
+/// `path`, from `sizes` or else from the `Content-Length` of a `HEAD`
+/// request, which is then cached.
+///
+/// Returns `None` if the request fails or does not report a size.
+async fn probe_size(client: &Client, url: &Url, path: &str, sizes: &SizeCache) -> Option<u64> {
+    if let Some(&size) = sizes.lock().unwrap().get(path) {
+        return Some(size);
+    }
+    let response = client.head(url.clone()).send().await.ok()?;
+    let size = response
+        .error_for_status()
+        .ok()?
+        .headers()
+        .get(CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    debug!("{url}: {size} bytes");
+    sizes.lock().unwrap().insert(path.to_string(), size);
+    Some(size)
+}
+
+/// Measure the download rate of `mirror` in bytes per second, downloading
+/// the database of `probe.repo`.
+///
+/// With `probe.bytes`, the size of the database is first learnt from `sizes`
+/// or a `HEAD` request: a larger file is only downloaded in part, by
+/// requesting a range of that many bytes, while a smaller one is downloaded
+/// in full. If the size is unknown, the download stops after that many bytes.
+///
+/// The downloaded bytes are counted in `downloaded`. Fails if the download
+/// fails or times out, as well as for mirrors whose protocol cannot be
+/// downloaded over HTTP, such as rsync.
+pub(crate) async fn measure_rate(
+    client: &Client,
+    mirror: &Mirror,
+    probe: &ProbeOptions,
+    sizes: &SizeCache,
+    downloaded: &ByteCounter,
+) -> Result<f64, ProbeError> {
+    let url = probe_url(&mirror.url, &probe.repo)
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or(ProbeError::UnsupportedProtocol)?;
+    let mut request = client.get(url.clone());
+    let mut limit = probe.bytes;
+    if let Some(bytes) = probe.bytes.filter(|&bytes| bytes > 0) {
+        match probe_size(client, &url, &probe_path(&probe.repo), sizes).await {
+            Some(size) if size > bytes => {
+                request = request.header(RANGE, format!("bytes=0-{}", bytes - 1));
+            }
+            Some(_) => limit = None,
+            None => (),
+        }
+    }
+    let start = Instant::now();
+    let response = request.send().await?.error_for_status()?;
+    let read = read_body(response, limit, downloaded).await?;
+    Ok(read as f64 / start.elapsed().as_secs_f64().max(f64::EPSILON))
+}
+
+/// Run `probe` on each of `mirrors` with at most `threads` probes at a time,
+/// returning the results in the order of `mirrors`.
+///
+/// Probes run one after another unless `threads` is greater than one; a
+/// probe that panics yields `None`. Whenever a probe ends, `progress` is
+/// called with the number of probes ended so far.
+pub(crate) async fn probe_each<T, F, Fut>(
+    mirrors: &[Mirror],
+    threads: usize,
+    probe: F,
+    mut progress: impl FnMut(usize),
+) -> Vec<Option<T>>
+where
+    T: Send + 'static,
+    F: Fn(Mirror) -> Fut,
+    Fut: Future<Output = T> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(threads.max(1)));
+    let mut probes = JoinSet::new();
+    for (index, mirror) in mirrors.iter().enumerate() {
+        let (permits, probe) = (permits.clone(), probe(mirror.clone()));
+        probes.spawn(async move {
+            let _permit = permits.acquire().await.ok()?;
+            Some((index, probe.await))
+        });
+    }
+    let mut results: Vec<_> = mirrors.iter().map(|_| None).collect();
+    let mut ended = 0;
+    while let Some(probe) = probes.join_next().await {
+        if let Ok(Some((index, result))) = probe {
+            results[index] = Some(result);
+        }
+        ended += 1;
+        progress(ended);
+    }
+    results
+}
+
+/// Report on stderr that `rated` of `total` mirrors have been rated.
+///
+/// On a terminal the counter is redrawn in place, otherwise each update is
+/// written on a line of its own.
+pub(crate) fn report_progress(rated: usize, total: usize) {
+    let mut stderr = stderr().lock();
+    let _ = if !stderr.is_terminal() {
+        writeln!(stderr, "rated {rated}/{total}")
+    } else if rated < total {
+        write!(stderr, "\rrated {rated}/{total}").and_then(|()| stderr.flush())
+    } else {
+        writeln!(stderr, "\rrated {rated}/{total}")
+    };
+}
+
+/// How each mirror is probed to measure its rate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ProbeOptions {
+    /// Repository whose database is downloaded.
+    pub(crate) repo: String,
+    /// Stop each download after this many bytes.
+    pub(crate) bytes: Option<u64>,
+    /// Number of downloads per mirror, of which the median rate is kept.
+    pub(crate) samples: usize,
+    /// Drop the mirrors that fail TLS validation instead of leaving them
+    /// unrated.
+    pub(crate) require_valid_tls: bool,
+}
+
+impl From<&Arguments> for ProbeOptions {
+    fn from(arguments: &Arguments) -> Self {
+        ProbeOptions {
+            repo: arguments.rate_repo.clone(),
+            bytes: arguments.rate_probe_bytes,
+            samples: arguments.rate_samples,
+            require_valid_tls: arguments.require_valid_tls,
+        }
+    }
+}
+
+/// The median of `rates`, averaging the middle two of an even number.
+pub(crate) fn median(mut rates: Vec<f64>) -> Option<f64> {
+    rates.sort_by(f64::total_cmp);
+    let middle = rates.len() / 2;
+    match rates.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(rates[middle]),
+        _ => Some((rates[middle - 1] + rates[middle]) / 2.0),
+    }
+}
+
+/// Path of the file caching the rates of mirrors across runs, relative to
+/// the cache directory.
+pub(crate) const RATE_CACHE_NAME: &str = "reflector-rs/rates.json";
+
+/// Rates of mirrors in bytes per second, by mirror URL.
+pub(crate) type RateHistory = HashMap<String, f64>;
+
+/// Blend the rate of each rated mirror with its rate in `history` as an
+/// exponential moving average, weighing the new rate by `alpha`, and record
+/// the blended rates in `history`.
+///
+/// Mirrors without a rate in `history` keep their new rate, and unrated
+/// mirrors stay unrated without affecting `history`.
+pub(crate) fn smooth_rates(mirrors: &mut [Mirror], history: &mut RateHistory, alpha: f64) {
+    for mirror in mirrors {
+        let Some(rate) = mirror.rate else {
+            continue;
+        };
+        let key = mirror.url.to_string();
+        let blended = match history.get(&key) {
+            Some(previous) => alpha * rate + (1.0 - alpha) * previous,
+            None => rate,
+        };
+        debug!("{}: {rate:.0} B/s, {blended:.0} B/s on average", mirror.url);
+        mirror.rate = Some(blended);
+        history.insert(key, blended);
+    }
+}
+
+/// Rate `mirrors` with at most `threads` mirrors probed at a time, storing
+/// the results in [`Mirror::rate`].
+///
+/// The order of `mirrors` is kept regardless of which download ends first.
+/// Whenever a mirror is rated, `progress` is called with the number of
+/// mirrors rated so far and the total, e.g. [`report_progress`]. Each mirror
+/// is downloaded `probe.samples` times in a row and rated by the median of
+/// the successful downloads. A TLS error, such as an invalid certificate,
+/// ends the sampling of a mirror, which is dropped from `mirrors` if
+/// `probe.require_valid_tls`.
+pub(crate) async fn rate_mirrors(
+    mirrors: &mut Vec<Mirror>,
+    http: HttpOptions,
+    threads: usize,
+    probe: ProbeOptions,
+    mut progress: impl FnMut(usize, usize),
+) -> reqwest::Result<()> {
+    let total = mirrors.len();
+    info!("rating {total} mirrors");
+    let client = client_builder(&http)?.build()?;
+    let sizes = Arc::new(SizeCache::default());
+    let rates = probe_each(
+        mirrors,
+        threads,
+        |mirror| {
+            let (client, probe, sizes) = (client.clone(), probe.clone(), sizes.clone());
+            let downloaded = http.downloaded.clone();
+            async move {
+                let mut rates = Vec::new();
+                for _ in 0..probe.samples.max(1) {
+                    match measure_rate(&client, &mirror, &probe, &sizes, &downloaded).await {
+                        Ok(rate) => rates.push(rate),
+                        Err(err @ ProbeError::Tls(_)) => return Err(err),
+                        Err(err) => debug!("{}: {err}", mirror.url),
+                    }
+                }
+                let rate = median(rates);
+                match rate {
+                    Some(rate) => debug!("{}: {rate:.0} B/s", mirror.url),
+                    None => debug!("{}: failed to rate", mirror.url),
+                }
+                Ok(rate)
+            }
+        },
+        |rated| progress(rated, total),
+    )
+    .await;
+    let mut rated = Vec::with_capacity(total);
+    for (mut mirror, rate) in mirrors.drain(..).zip(rates) {
+        mirror.rate = match rate {
+            Some(Err(err)) if probe.require_valid_tls => {
+                warn!("dropping {}: {err}", mirror.url);
+                continue;
+            }
+            Some(Err(err)) => {
+                debug!("{}: {err}", mirror.url);
+                None
+            }
+            rate => rate.and_then(Result::ok).flatten(),
+        };
+        rated.push(mirror);
+    }
+    *mirrors = rated;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        filter::filter_fastest,
+        test_utils::{mirror, response, serve, serve_recording},
+    };
+
+    /// Probe the database of core once, in full.
+    fn once() -> ProbeOptions {
+        ProbeOptions {
+            repo: "core".to_string(),
+            bytes: None,
+            samples: 1,
+            require_valid_tls: false,
+        }
+    }
+
+    fn http() -> HttpOptions {
+        HttpOptions {
+            connection: Duration::from_secs(1),
+            tls: Duration::from_secs(1),
+            download: Duration::from_secs(1),
+            retries: 0,
+            proxy: None,
+            downloaded: ByteCounter::default(),
+        }
+    }
+
+    /// Serve TLS on a local port with a self-signed certificate, which the
+    /// client rejects, returning the base URL of the server.
+    fn serve_self_signed() -> Url {
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("../tests/fixtures/self-signed.crt"),
+            include_bytes!("../tests/fixtures/self-signed.key"),
+        )
+        .unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("https://{}/", listener.local_addr().unwrap())).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = acceptor.accept(stream);
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_probe_url() {
+        let url = |url, repo| probe_url(&Url::parse(url).unwrap(), repo).map(String::from);
+        assert_eq!(
+            url("https://mirror.example.com/archlinux/", "core").unwrap(),
+            "https://mirror.example.com/archlinux/core/os/x86_64/core.db"
+        );
+        assert_eq!(
+            url("https://mirror.example.com/archlinux", "core").unwrap(),
+            "https://mirror.example.com/archlinux/core/os/x86_64/core.db"
+        );
+        assert_eq!(
+            url("https://mirror.example.com/archlinux/", "extra").unwrap(),
+            "https://mirror.example.com/archlinux/extra/os/x86_64/extra.db"
+        );
+        assert_eq!(url("https://mirror.example.com/archlinux/", "a/b"), None);
+        assert_eq!(url("https://mirror.example.com/archlinux/", ".."), None);
+        assert_eq!(url("https://mirror.example.com/archlinux/", "?x"), None);
+        assert_eq!(url("https://mirror.example.com/archlinux/", "#x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_probe_sizing() {
+        let head = |size: u64| {
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {size}\r\nConnection: close\r\n\r\n")
+                .into_bytes()
+        };
+        let probe = ProbeOptions {
+            bytes: Some(1000),
+            ..once()
+        };
+        let ranges = |requests: &[String]| {
+            requests
+                .iter()
+                .map(|request| {
+                    let method = request.split(' ').next().unwrap().to_string();
+                    let range = request.lines().find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("range: ")
+                            .map(String::from)
+                    });
+                    (method, range)
+                })
+                .collect::<Vec<_>>()
+        };
+        let get = |range: Option<&str>| ("GET".to_string(), range.map(String::from));
+        let head_request = ("HEAD".to_string(), None);
+
+        // A larger file is downloaded in part, and its size only requested
+        // from the first mirror.
+        let partial = response("206 Partial Content", &[], &[0; 1000]);
+        let (first, first_requests) = serve_recording(vec![head(1 << 20), partial.clone()]).await;
+        let (second, second_requests) = serve_recording(vec![partial]).await;
+        let mut mirrors: Vec<_> = [first, second]
+            .into_iter()
+            .map(|url| Mirror { url, ..mirror() })
+            .collect();
+        rate_mirrors(&mut mirrors, http(), 1, probe.clone(), |_, _| ())
+            .await
+            .unwrap();
+        assert!(mirrors.iter().all(|mirror| mirror.rate.is_some()));
+        assert_eq!(
+            ranges(&first_requests.lock().unwrap()),
+            [head_request.clone(), get(Some("bytes=0-999"))]
+        );
+        assert_eq!(
+            ranges(&second_requests.lock().unwrap()),
+            [get(Some("bytes=0-999"))]
+        );
+
+        // A smaller file is downloaded in full.
+        let (url, requests) =
+            serve_recording(vec![head(500), response("200 OK", &[], &[0; 500])]).await;
+        let mut mirrors = vec![Mirror { url, ..mirror() }];
+        rate_mirrors(&mut mirrors, http(), 1, probe, |_, _| ())
+            .await
+            .unwrap();
+        assert!(mirrors[0].rate.is_some());
+        assert_eq!(ranges(&requests.lock().unwrap()), [head_request, get(None)]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_repo() {
+        let (url, requests) = serve_recording(vec![response("200 OK", &[], &[0; 1024])]).await;
+        let mut mirrors = vec![Mirror { url, ..mirror() }];
+        let arguments = Arguments::parse_from([crate::NAME, "--rate-repo", "extra"]);
+        let probe = ProbeOptions::from(&arguments);
+        rate_mirrors(&mut mirrors, http(), 1, probe, |_, _| ())
+            .await
+            .unwrap();
+        assert!(mirrors[0].rate.is_some());
+        let requests = requests.lock().unwrap();
+        assert!(
+            requests[0].starts_with("GET /extra/os/x86_64/extra.db HTTP/1.1\r\n"),
+            "{requests:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_mirrors() {
+        let served = serve(vec![response("200 OK", &[], &[0; 4096])]).await;
+        let failing = serve(vec![response("404 Not Found", &[], b"")]).await;
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap()
+        };
+        let rsync = Url::parse("rsync://mirror.example.com/archlinux/").unwrap();
+        let mut mirrors: Vec<_> = [closed, rsync, served.clone(), failing]
+            .into_iter()
+            .map(|url| Mirror { url, ..mirror() })
+            .collect();
+        rate_mirrors(&mut mirrors, http(), 1, once(), |_, _| ())
+            .await
+            .unwrap();
+
+        let rated: Vec<_> = mirrors.iter().map(|mirror| mirror.rate.is_some()).collect();
+        assert_eq!(rated, [false, false, true, false]);
+        let fastest = filter_fastest(mirrors, 2, 0);
+        assert_eq!(fastest.len(), 2);
+        assert_eq!(fastest[0].url, served);
+    }
+
+    #[tokio::test]
+    async fn test_tls_errors() {
+        let client = client_builder(&http()).unwrap().build().unwrap();
+        let self_signed = Mirror {
+            url: serve_self_signed(),
+            ..mirror()
+        };
+        let err = measure_rate(
+            &client,
+            &self_signed,
+            &once(),
+            &SizeCache::default(),
+            &ByteCounter::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(&err, ProbeError::Tls(reason) if reason.contains("certificate")),
+            "{err:?}"
+        );
+
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("https://{}/", listener.local_addr().unwrap())).unwrap()
+        };
+        let err = client.get(closed).send().await.unwrap_err();
+        assert!(err.is_connect());
+        assert!(tls_cause(&err).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_require_valid_tls() {
+        let served = serve(vec![response("200 OK", &[], &[0; 1024]); 2]).await;
+        let mirrors: Vec<_> = [served.clone(), serve_self_signed()]
+            .into_iter()
+            .map(|url| Mirror { url, ..mirror() })
+            .collect();
+        let rated = |mut mirrors: Vec<Mirror>, require_valid_tls| async move {
+            let probe = ProbeOptions {
+                require_valid_tls,
+                ..once()
+            };
+            rate_mirrors(&mut mirrors, http(), 1, probe, |_, _| ())
+                .await
+                .unwrap();
+            mirrors
+                .into_iter()
+                .map(|mirror| (mirror.url, mirror.rate.is_some()))
+                .collect::<Vec<_>>()
+        };
+        let unrated = rated(mirrors.clone(), false).await;
+        assert_eq!(unrated.len(), 2);
+        assert_eq!(unrated[1], (mirrors[1].url.clone(), false));
+        assert_eq!(rated(mirrors, true).await, [(served, true)]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_mirrors_in_parallel() {
+        let mut urls = Vec::new();
+        for status in [
+            "200 OK",
+            "404 Not Found",
+            "200 OK",
+            "500 Internal Server Error",
+        ] {
+            let probe = response(status, &[], &[0; 1024]);
+            urls.push(serve(vec![probe.clone(), probe]).await);
+        }
+        let mirrors: Vec<_> = urls
+            .into_iter()
+            .map(|url| Mirror { url, ..mirror() })
+            .collect();
+        let rated = |mut mirrors: Vec<Mirror>, threads| async move {
+            rate_mirrors(&mut mirrors, http(), threads, once(), |_, _| ())
+                .await
+                .unwrap();
+            mirrors
+                .into_iter()
+                .map(|mirror| (mirror.url, mirror.rate.is_some()))
+                .collect::<Vec<_>>()
+        };
+        let sequential = rated(mirrors.clone(), 1).await;
+        assert_eq!(rated(mirrors, 2).await, sequential);
+        let successes: Vec<_> = sequential.iter().map(|(_, rated)| *rated).collect();
+        assert_eq!(successes, [true, false, true, false]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_mirrors_progress() {
+        let rsync = Url::parse("rsync://mirror.example.com/archlinux/").unwrap();
+        let mut mirrors = vec![
+            Mirror {
+                url: rsync,
+                ..mirror()
+            };
+            3
+        ];
+        let mut calls = Vec::new();
+        rate_mirrors(&mut mirrors, http(), 2, once(), |rated, total| {
+            calls.push((rated, total))
+        })
+        .await
+        .unwrap();
+        assert_eq!(calls, [(1, 3), (2, 3), (3, 3)]);
+    }
+
+    #[tokio::test]
+    async fn test_probe_bytes() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+        };
+
+        // The server counts the bytes it manages to write until the client
+        // drops the connection, which is far fewer than the whole body.
+        const TOTAL: u64 = 64 * 1024 * 1024;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let written = Arc::new(AtomicU64::new(0));
+        let counter = written.clone();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.read(&mut [0; 1024]).await;
+            let headers = format!("HTTP/1.1 200 OK\r\nContent-Length: {TOTAL}\r\n\r\n");
+            stream.write_all(headers.as_bytes()).await.unwrap();
+            let chunk = [0; 64 * 1024];
+            while counter.load(Ordering::SeqCst) < TOTAL {
+                if stream.write_all(&chunk).await.is_err() {
+                    break;
+                }
+                counter.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+            }
+        });
+
+        let limited = Client::new().get(url).send().await.unwrap();
+        let downloaded = ByteCounter::default();
+        assert_eq!(
+            read_body(limited, Some(1000), &downloaded).await.unwrap(),
+            1000
+        );
+        assert!(downloaded.get() >= 1000);
+        server.await.unwrap();
+        let written = written.load(Ordering::SeqCst);
+        assert!(written < TOTAL / 4, "{written} of {TOTAL} bytes written");
+
+        let body = vec![0; 256 * 1024];
+        let url = serve(vec![response("200 OK", &[], &body)]).await;
+        let full = Client::new().get(url).send().await.unwrap();
+        let downloaded = ByteCounter::default();
+        assert_eq!(
+            read_body(full, None, &downloaded).await.unwrap(),
+            body.len() as u64
+        );
+        assert_eq!(downloaded.get(), body.len() as u64);
+    }
+
+    #[test]
+    fn test_smooth_rates() {
+        let mut history = RateHistory::new();
+        let mut rate = |rate| {
+            let mut mirrors = vec![Mirror { rate, ..mirror() }];
+            smooth_rates(&mut mirrors, &mut history, 0.3);
+            mirrors[0].rate
+        };
+        assert_eq!(rate(Some(1000.0)), Some(1000.0));
+        for _ in 0..5 {
+            assert_eq!(rate(Some(1000.0)), Some(1000.0));
+        }
+        // A single slow sample only takes off alpha of the difference.
+        assert_eq!(rate(Some(10.0)), Some(703.0));
+        assert_eq!(rate(None), None);
+        let recovered = rate(Some(1000.0)).unwrap();
+        assert!((recovered - 792.1).abs() < 1e-9, "{recovered}");
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(vec![10.0, 30.0, 20.0]), Some(20.0));
+        assert_eq!(median(vec![40.0, 10.0, 30.0, 20.0]), Some(25.0));
+        assert_eq!(median(vec![5.0]), Some(5.0));
+        assert_eq!(median(Vec::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_samples() {
+        let probe = response("200 OK", &[], &[0; 1024]);
+        let failure = response("404 Not Found", &[], b"");
+        let (sampled, sampled_requests) =
+            serve_recording(vec![probe.clone(), failure.clone(), probe]).await;
+        let (failing, failing_requests) = serve_recording(vec![failure; 3]).await;
+        let mut mirrors: Vec<_> = [sampled, failing]
+            .into_iter()
+            .map(|url| Mirror { url, ..mirror() })
+            .collect();
+        let probe = ProbeOptions {
+            samples: 3,
+            ..once()
+        };
+        let mut probed = 0;
+        rate_mirrors(&mut mirrors, http(), 1, probe, |_, _| probed += 1)
+            .await
+            .unwrap();
+        assert_eq!(probed, 2);
+        assert_eq!(sampled_requests.lock().unwrap().len(), 3);
+        assert_eq!(failing_requests.lock().unwrap().len(), 3);
+        // The failed sample is left out of the median rather than failing
+        // the mirror, which is only unrated if every sample fails.
+        assert!(mirrors[0].rate.is_some());
+        assert_eq!(mirrors[1].rate, None);
+    }
+}
@@ -0,0 +1,814 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::{
+    cli::{CountryAlias, CountryOrder, Protocol, SortKey, SortType},
+    country::matches_country,
+    timestamp::parse_rfc3339,
+    Mirror,
+};
+
+/// Compare two optional keys, putting `None` last regardless of `descending`.
+fn compare_present<T: PartialOrd>(a: Option<T>, b: Option<T>, descending: bool) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let ordering = a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        }
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Amounts of MirrorStatus score in hours, of standard deviation of the
+/// check durations in seconds, and of fraction of failed checks, that each
+/// add one to the [`reliability_penalty`].
+const RELIABILITY_UNITS: (f64, f64, f64) = (1.0, 0.5, 0.1);
+
+/// The reliability penalty of `mirror`, lower being more reliable.
+///
+/// It sums the MirrorStatus score, the standard deviation of the check
+/// durations and the fraction of failed checks, each divided by its unit in
+/// [`RELIABILITY_UNITS`] so that none dominates merely by its scale: an hour
+/// of score weighs as much as half a second of deviation or 10% of failed
+/// checks. Mirrors without a score or duration deviation have no penalty and
+/// are sorted last.
+///
+/// The completion is taken as a fraction, as normalized by
+/// [`filter_by_completion`](crate::filter::filter_by_completion).
+pub(crate) fn reliability_penalty(mirror: &Mirror) -> Option<f64> {
+    let (score_unit, stddev_unit, failure_unit) = RELIABILITY_UNITS;
+    Some(
+        mirror.score? / score_unit
+            + mirror.duration_stddev? / stddev_unit
+            + (1.0 - mirror.completion_pct) / failure_unit,
+    )
+}
+
+/// Compare two mirrors by a single key in its natural direction, reversed if
+/// `descending`.
+///
+/// Naturally, age puts the most recently synchronized mirrors first, rate
+/// the fastest, score and delay put the lowest values first, reliability
+/// the lowest [`reliability_penalty`], and country is alphabetical. Mirrors
+/// missing the key, including those whose `last_sync` is absent or cannot be
+/// parsed, come last in either direction.
+pub(crate) fn compare_by(a: &Mirror, b: &Mirror, sort: &SortType, descending: bool) -> Ordering {
+    match sort {
+        SortType::Age => {
+            let last_sync = |mirror: &Mirror| mirror.last_sync.as_deref().and_then(parse_rfc3339);
+            compare_present(last_sync(a), last_sync(b), !descending)
+        }
+        SortType::Score => compare_present(a.score, b.score, descending),
+        SortType::Delay => compare_present(a.delay, b.delay, descending),
+        SortType::Country => compare_present(Some(&a.country), Some(&b.country), descending),
+        SortType::Rate => compare_present(a.rate, b.rate, !descending),
+        SortType::Reliability => {
+            compare_present(reliability_penalty(a), reliability_penalty(b), descending)
+        }
+    }
+}
+
+/// The rates by which `order` ranks countries, by country code, or `None`
+/// if it ranks them by name.
+///
+/// Countries without any rated mirror have no rate.
+fn country_rates(mirrors: &[Mirror], order: CountryOrder) -> Option<HashMap<String, f64>> {
+    if order == CountryOrder::Name {
+        return None;
+    }
+    let mut rates: HashMap<String, Vec<f64>> = HashMap::new();
+    for mirror in mirrors {
+        if let Some(rate) = mirror.rate {
+            let country = mirror.country_code.clone();
+            rates.entry(country).or_default().push(rate);
+        }
+    }
+    let rates = rates.into_iter().map(|(country, rates)| {
+        let rate = match order {
+            CountryOrder::AverageRate => rates.iter().sum::<f64>() / rates.len() as f64,
+            _ => rates.into_iter().fold(f64::MIN, f64::max),
+        };
+        (country, rate)
+    });
+    Some(rates.collect())
+}
+
+/// Compare the countries of two mirrors by their `rates`, fastest first,
+/// reversed if `descending`, then alphabetically.
+fn compare_country_rates(
+    a: &Mirror,
+    b: &Mirror,
+    rates: &HashMap<String, f64>,
+    descending: bool,
+) -> Ordering {
+    let rate = |mirror: &Mirror| rates.get(&mirror.country_code);
+    compare_present(rate(a), rate(b), !descending)
+        .then_with(|| compare_by(a, b, &SortType::Country, descending))
+}
+
+/// Stably sort `mirrors` by `keys`, each key breaking ties of the previous ones.
+///
+/// The country key orders countries according to `country_order`.
+pub(crate) fn sort_by_keys(mirrors: &mut [Mirror], keys: &[SortKey], country_order: CountryOrder) {
+    let rates = country_rates(mirrors, country_order);
+    mirrors.sort_by(|a, b| {
+        keys.iter()
+            .map(|key| match (&key.sort, &rates) {
+                (SortType::Country, Some(rates)) => {
+                    compare_country_rates(a, b, rates, key.descending)
+                }
+                (sort, _) => compare_by(a, b, sort, key.descending),
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+}
+
+/// Compare two mirrors by URL, to order mirrors that are otherwise equal the
+/// same way regardless of the order of the mirror data.
+pub(crate) fn compare_urls(a: &Mirror, b: &Mirror) -> Ordering {
+    a.url.as_str().cmp(b.url.as_str())
+}
+
+/// Position of the protocol of `mirror` in `protocols`, or the length of
+/// `protocols` if it is not listed.
+pub(crate) fn protocol_rank(mirror: &Mirror, protocols: &[Protocol]) -> usize {
+    protocols
+        .iter()
+        .position(|protocol| protocol.as_str().eq_ignore_ascii_case(&mirror.protocol))
+        .unwrap_or(protocols.len())
+}
+
+/// Stably sort `mirrors` by `sort` in its natural direction, breaking ties
+/// by the order of their protocol in `protocols`.
+///
+/// When sorting by country with a `country_order`, such as the countries
+/// given to `--country`, mirrors are sorted by the first token they match,
+/// and then alphabetically. Within a country, mirrors are sorted by score.
+/// Mirrors matching no token are placed at the glob `*` if it is among the
+/// tokens, and last otherwise. Likewise, mirrors whose protocol is not in
+/// `protocols` come after the others. Remaining ties are broken by URL.
+///
+/// Country tokens resolve the `overrides` of "--country-alias" as when
+/// filtering, so that both agree on which country an alias names.
+///
+/// If `order_by` ranks countries by rate instead, the countries with the
+/// highest rate come first regardless of `country_order`, and mirrors are
+/// sorted by rate within a country.
+pub(crate) fn sort_mirrors(
+    mirrors: &mut [Mirror],
+    sort: SortType,
+    country_order: Option<&[String]>,
+    order_by: CountryOrder,
+    overrides: &[CountryAlias],
+    protocols: &[Protocol],
+) {
+    let rates = country_rates(mirrors, order_by);
+    let country_rank = |mirror: &Mirror| {
+        country_order.map(|order| {
+            order
+                .iter()
+                .position(|token| matches_country(mirror, token, overrides))
+                .or_else(|| order.iter().position(|token| token == "*"))
+                .unwrap_or(order.len())
+        })
+    };
+    mirrors.sort_by(|a, b| {
+        let ordering = match (&sort, &rates) {
+            (SortType::Country, Some(rates)) => compare_country_rates(a, b, rates, false)
+                .then_with(|| compare_by(a, b, &SortType::Rate, false)),
+            (SortType::Country, None) => country_rank(a)
+                .cmp(&country_rank(b))
+                .then_with(|| compare_by(a, b, &SortType::Country, false))
+                .then_with(|| compare_by(a, b, &SortType::Score, false)),
+            (sort, _) => compare_by(a, b, sort, false),
+        };
+        ordering
+            .then_with(|| protocol_rank(a, protocols).cmp(&protocol_rank(b, protocols)))
+            .then_with(|| compare_urls(a, b))
+    });
+}
+
+/// Stably move IPv6-capable mirrors ahead of IPv4-only ones, keeping the
+/// previous order within each group.
+///
+/// Applied after another sort, this makes IPv6 support its primary key.
+pub(crate) fn prefer_ipv6(mirrors: &mut [Mirror]) {
+    mirrors.sort_by_key(|mirror| !mirror.ipv6);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::CompletionScale, filter::filter_by_completion, test_utils::mirror};
+
+    #[test]
+    fn test_sort_by_keys() {
+        let keys: Vec<SortKey> = "score,-delay"
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut mirrors: Vec<_> = [
+            (Some(2.0), Some(60)),
+            (None, Some(0)),
+            (Some(1.0), Some(60)),
+            (Some(1.0), Some(120)),
+            (Some(1.0), None),
+        ]
+        .into_iter()
+        .map(|(score, delay)| Mirror {
+            score,
+            delay,
+            ..mirror()
+        })
+        .collect();
+        sort_by_keys(&mut mirrors, &keys, CountryOrder::Name);
+        let order: Vec<_> = mirrors
+            .iter()
+            .map(|mirror| (mirror.score, mirror.delay))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (Some(1.0), Some(120)),
+                (Some(1.0), Some(60)),
+                (Some(1.0), None),
+                (Some(2.0), Some(60)),
+                (None, Some(0)),
+            ]
+        );
+        assert!("bogus".parse::<SortKey>().is_err());
+    }
+
+    #[test]
+    fn test_sort_by_keys_with_rate() {
+        let keys: Vec<SortKey> = "score,-rate,delay"
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut mirrors: Vec<_> = [
+            (Some(1.0), Some(100.0), Some(60)),
+            (Some(1.0), Some(300.0), Some(60)),
+            (Some(0.5), None, Some(60)),
+            (Some(1.0), Some(100.0), Some(0)),
+        ]
+        .into_iter()
+        .map(|(score, rate, delay)| Mirror {
+            score,
+            rate,
+            delay,
+            ..mirror()
+        })
+        .collect();
+        sort_by_keys(&mut mirrors, &keys, CountryOrder::Name);
+        let order: Vec<_> = mirrors
+            .iter()
+            .map(|mirror| (mirror.score, mirror.rate, mirror.delay))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (Some(0.5), None, Some(60)),
+                (Some(1.0), Some(100.0), Some(0)),
+                (Some(1.0), Some(100.0), Some(60)),
+                (Some(1.0), Some(300.0), Some(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_mirrors() {
+        let mut mirrors: Vec<_> = [
+            (Some("2023-09-20T09:00:00Z"), Some(2.0)),
+            (None, Some(1.0)),
+            (Some("2023-09-20T11:00:00Z"), None),
+            (Some("2023-09-20T10:00:00Z"), Some(0.5)),
+        ]
+        .into_iter()
+        .map(|(last_sync, score)| Mirror {
+            last_sync: last_sync.map(ToString::to_string),
+            score,
+            ..mirror()
+        })
+        .collect();
+
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Age,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        let last_syncs: Vec<_> = mirrors.iter().map(|m| m.last_sync.as_deref()).collect();
+        assert_eq!(
+            last_syncs,
+            [
+                Some("2023-09-20T11:00:00Z"),
+                Some("2023-09-20T10:00:00Z"),
+                Some("2023-09-20T09:00:00Z"),
+                None,
+            ]
+        );
+
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Score,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        let scores: Vec<_> = mirrors.iter().map(|m| m.score).collect();
+        assert_eq!(scores, [Some(0.5), Some(1.0), Some(2.0), None]);
+    }
+
+    #[test]
+    fn test_sort_by_delay() {
+        let mut mirrors: Vec<_> = [None, Some(600), None, Some(60), Some(3600)]
+            .into_iter()
+            .enumerate()
+            .map(|(index, delay)| Mirror {
+                url: format!("https://mirror{index}.example.com/")
+                    .parse()
+                    .unwrap(),
+                delay,
+                ..mirror()
+            })
+            .collect();
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Delay,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        let delays: Vec<_> = mirrors.iter().map(|m| m.delay).collect();
+        assert_eq!(delays, [Some(60), Some(600), Some(3600), None, None]);
+        assert_eq!(mirrors[3].url.as_str(), "https://mirror0.example.com/");
+
+        let keys = ["-delay".parse().unwrap()];
+        sort_by_keys(&mut mirrors, &keys, CountryOrder::Name);
+        let delays: Vec<_> = mirrors.iter().map(|m| m.delay).collect();
+        assert_eq!(delays, [Some(3600), Some(600), Some(60), None, None]);
+    }
+
+    #[test]
+    fn test_sort_by_preferred_country() {
+        let mut mirrors: Vec<_> = [
+            ("Germany", "DE"),
+            ("Sweden", "SE"),
+            ("Austria", "AT"),
+            ("Denmark", "DK"),
+        ]
+        .into_iter()
+        .map(|(country, code)| Mirror {
+            country: country.to_string(),
+            country_code: code.to_string(),
+            ..mirror()
+        })
+        .collect();
+        let order = ["se".to_string(), "Denmark".to_string()];
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Country,
+            Some(&order),
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        let countries: Vec<_> = mirrors.iter().map(|m| m.country.as_str()).collect();
+        assert_eq!(countries, ["Sweden", "Denmark", "Austria", "Germany"]);
+
+        let order = ["nordic".to_string()];
+        let overrides = ["nordic=DK".parse().unwrap()];
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Country,
+            Some(&order),
+            CountryOrder::Name,
+            &overrides,
+            &[],
+        );
+        assert_eq!(mirrors[0].country, "Denmark");
+    }
+
+    #[test]
+    fn test_sort_by_country_glob() {
+        let mut mirrors: Vec<_> = ["DK", "US", "SE", "DE", "DK", "SE"]
+            .into_iter()
+            .map(|code| Mirror {
+                country: code.to_string(),
+                country_code: code.to_string(),
+                ..mirror()
+            })
+            .collect();
+        let order = ["se".to_string(), "*".to_string(), "dk".to_string()];
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Country,
+            Some(&order),
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        let codes: Vec<_> = mirrors.iter().map(|m| m.country_code.as_str()).collect();
+        assert_eq!(codes, ["SE", "SE", "DE", "US", "DK", "DK"]);
+    }
+
+    #[test]
+    fn test_sort_by_protocol_preference() {
+        let mut mirrors: Vec<_> = ["rsync", "http", "ftp", "https"]
+            .into_iter()
+            .map(|protocol| Mirror {
+                protocol: protocol.to_string(),
+                score: Some(1.0),
+                ..mirror()
+            })
+            .collect();
+        let protocols = |mirrors: &[Mirror]| {
+            mirrors
+                .iter()
+                .map(|mirror| mirror.protocol.clone())
+                .collect::<Vec<_>>()
+        };
+        let preference = [Protocol::Https, Protocol::Http, Protocol::Rsync];
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Score,
+            None,
+            CountryOrder::Name,
+            &[],
+            &preference,
+        );
+        assert_eq!(protocols(&mirrors), ["https", "http", "rsync", "ftp"]);
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Score,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[Protocol::Rsync],
+        );
+        assert_eq!(protocols(&mirrors), ["rsync", "https", "http", "ftp"]);
+    }
+
+    #[test]
+    fn test_sort_by_rate() {
+        let mut mirrors: Vec<_> = [Some(10.0), None, Some(30.0), Some(20.0)]
+            .into_iter()
+            .map(|rate| Mirror { rate, ..mirror() })
+            .collect();
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Rate,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        let rates: Vec<_> = mirrors.iter().map(|m| m.rate).collect();
+        assert_eq!(rates, [Some(30.0), Some(20.0), Some(10.0), None]);
+    }
+
+    #[test]
+    fn test_prefer_ipv6() {
+        let mut mirrors: Vec<_> = [(false, 0.5), (true, 3.0), (false, 1.0), (true, 2.0)]
+            .into_iter()
+            .map(|(ipv6, score)| Mirror {
+                ipv6,
+                score: Some(score),
+                ..mirror()
+            })
+            .collect();
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Score,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        prefer_ipv6(&mut mirrors);
+        let order: Vec<_> = mirrors.iter().map(|m| (m.ipv6, m.score)).collect();
+        assert_eq!(
+            order,
+            [
+                (true, Some(2.0)),
+                (true, Some(3.0)),
+                (false, Some(0.5)),
+                (false, Some(1.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_by_reliability() {
+        let mut mirrors: Vec<_> = [
+            ("https://noisy.example.com/", Some(1.0), Some(2.5), 1.0),
+            ("https://unknown.example.com/", Some(0.5), None, 1.0),
+            ("https://steady.example.com/", Some(1.0), Some(0.1), 1.0),
+            ("https://behind.example.com/", Some(1.0), Some(0.1), 0.5),
+        ]
+        .into_iter()
+        .map(|(url, score, duration_stddev, completion_pct)| Mirror {
+            url: url.parse().unwrap(),
+            score,
+            duration_stddev,
+            completion_pct,
+            ..mirror()
+        })
+        .collect();
+        let penalties: Vec<_> = mirrors.iter().map(reliability_penalty).collect();
+        let rounded: Vec<_> = penalties
+            .iter()
+            .map(|penalty| penalty.map(|penalty| (penalty * 10.0).round() / 10.0))
+            .collect();
+        assert_eq!(rounded, [Some(6.0), None, Some(1.2), Some(6.2)]);
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Reliability,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        let hosts: Vec<_> = mirrors.iter().filter_map(|m| m.url.host_str()).collect();
+        assert_eq!(
+            hosts,
+            [
+                "steady.example.com",
+                "noisy.example.com",
+                "behind.example.com",
+                "unknown.example.com",
+            ]
+        );
+
+        // Failed checks outweigh a score several hours worse.
+        let failing = Mirror {
+            score: Some(1.0),
+            duration_stddev: Some(0.0),
+            completion_pct: 0.5,
+            ..mirror()
+        };
+        let late = Mirror {
+            score: Some(4.0),
+            completion_pct: 1.0,
+            ..failing.clone()
+        };
+        assert!(reliability_penalty(&failing) > reliability_penalty(&late));
+    }
+
+    #[test]
+    fn test_sort_by_reliability_with_percent_completion() {
+        let mirrors = |completions: [f64; 3]| {
+            let mirrors = [
+                ("https://full.example.com/", 1.0),
+                ("https://half.example.com/", 0.5),
+                ("https://late.example.com/", 4.0),
+            ]
+            .into_iter()
+            .zip(completions)
+            .map(|((url, score), completion_pct)| Mirror {
+                url: url.parse().unwrap(),
+                score: Some(score),
+                duration_stddev: Some(0.0),
+                completion_pct,
+                ..mirror()
+            })
+            .collect();
+            let mut mirrors = filter_by_completion(mirrors, 0.0, CompletionScale::Auto);
+            sort_mirrors(
+                &mut mirrors,
+                SortType::Reliability,
+                None,
+                CountryOrder::Name,
+                &[],
+                &[],
+            );
+            mirrors
+                .iter()
+                .map(|mirror| (mirror.url.to_string(), reliability_penalty(mirror)))
+                .collect::<Vec<_>>()
+        };
+        let fractions = mirrors([1.0, 0.5, 1.0]);
+        assert_eq!(mirrors([100.0, 50.0, 100.0]), fractions);
+        let urls: Vec<_> = fractions.iter().map(|(url, _)| url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://full.example.com/",
+                "https://late.example.com/",
+                "https://half.example.com/",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_countries_by_rate() {
+        let mirrors: Vec<_> = [
+            ("Germany", "DE", Some(10.0)),
+            ("Austria", "AT", Some(20.0)),
+            ("France", "FR", Some(60.0)),
+            ("Sweden", "SE", None),
+            ("Germany", "DE", Some(100.0)),
+            ("France", "FR", Some(60.0)),
+        ]
+        .into_iter()
+        .map(|(country, code, rate)| Mirror {
+            country: country.to_string(),
+            country_code: code.to_string(),
+            rate,
+            ..mirror()
+        })
+        .collect();
+        let sorted = |order_by, keys: Option<&str>| {
+            let mut mirrors = mirrors.clone();
+            match keys {
+                Some(keys) => {
+                    let keys: Vec<SortKey> =
+                        keys.split(',').map(|key| key.parse().unwrap()).collect();
+                    sort_by_keys(&mut mirrors, &keys, order_by);
+                }
+                None => {
+                    // The order of "--country" is overridden by the rates.
+                    let order = ["SE".to_string(), "AT".to_string()];
+                    sort_mirrors(
+                        &mut mirrors,
+                        SortType::Country,
+                        Some(&order),
+                        order_by,
+                        &[],
+                        &[],
+                    );
+                }
+            }
+            mirrors
+                .into_iter()
+                .map(|mirror| (mirror.country_code, mirror.rate))
+                .collect::<Vec<_>>()
+        };
+        let rated = |code: &str, rate| (code.to_string(), Some(rate));
+        let unrated = ("SE".to_string(), None);
+        assert_eq!(
+            sorted(CountryOrder::Rate, None),
+            [
+                rated("DE", 100.0),
+                rated("DE", 10.0),
+                rated("FR", 60.0),
+                rated("FR", 60.0),
+                rated("AT", 20.0),
+                unrated.clone(),
+            ]
+        );
+        assert_eq!(
+            sorted(CountryOrder::AverageRate, None),
+            [
+                rated("FR", 60.0),
+                rated("FR", 60.0),
+                rated("DE", 100.0),
+                rated("DE", 10.0),
+                rated("AT", 20.0),
+                unrated.clone(),
+            ]
+        );
+        let countries = |mirrors: Vec<(String, Option<f64>)>| {
+            let mut countries: Vec<_> = mirrors.into_iter().map(|(code, _)| code).collect();
+            countries.dedup();
+            countries
+        };
+        assert_eq!(
+            countries(sorted(CountryOrder::Name, None)),
+            ["SE", "AT", "FR", "DE"]
+        );
+        assert_eq!(
+            countries(sorted(CountryOrder::Rate, Some("country,-rate"))),
+            ["DE", "FR", "AT", "SE"]
+        );
+        assert_eq!(
+            countries(sorted(CountryOrder::Rate, Some("-country"))),
+            ["AT", "FR", "DE", "SE"]
+        );
+        assert_eq!(
+            countries(sorted(CountryOrder::Name, Some("country"))),
+            ["AT", "FR", "DE", "SE"]
+        );
+    }
+
+    #[test]
+    fn test_sort_by_country_then_score() {
+        let mut mirrors: Vec<_> = [
+            ("SE", Some(2.0)),
+            ("DE", None),
+            ("DE", Some(3.0)),
+            ("SE", Some(1.0)),
+            ("DE", Some(0.5)),
+        ]
+        .into_iter()
+        .map(|(code, score)| Mirror {
+            country: code.to_string(),
+            country_code: code.to_string(),
+            score,
+            ..mirror()
+        })
+        .collect();
+        let order = |mirrors: &[Mirror]| {
+            mirrors
+                .iter()
+                .map(|m| (m.country_code.clone(), m.score))
+                .collect::<Vec<_>>()
+        };
+        let de = |score| ("DE".to_string(), score);
+        let se = |score| ("SE".to_string(), score);
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Country,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        assert_eq!(
+            order(&mirrors),
+            [
+                de(Some(0.5)),
+                de(Some(3.0)),
+                de(None),
+                se(Some(1.0)),
+                se(Some(2.0))
+            ]
+        );
+        let preferred = ["se".to_string(), "de".to_string()];
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Country,
+            Some(&preferred),
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        assert_eq!(
+            order(&mirrors),
+            [
+                se(Some(1.0)),
+                se(Some(2.0)),
+                de(Some(0.5)),
+                de(Some(3.0)),
+                de(None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sort_by_age_without_last_sync() {
+        let mut mirrors: Vec<_> = [
+            None,
+            Some("2023-09-20T09:00:00Z"),
+            Some("yesterday"),
+            Some("2023-09-20T11:00:00Z"),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(index, last_sync)| Mirror {
+            url: format!("https://mirror{index}.example.com/")
+                .parse()
+                .unwrap(),
+            last_sync: last_sync.map(ToString::to_string),
+            ..mirror()
+        })
+        .collect();
+        sort_mirrors(
+            &mut mirrors,
+            SortType::Age,
+            None,
+            CountryOrder::Name,
+            &[],
+            &[],
+        );
+        let last_syncs: Vec<_> = mirrors.iter().map(|m| m.last_sync.as_deref()).collect();
+        assert_eq!(
+            last_syncs,
+            [
+                Some("2023-09-20T11:00:00Z"),
+                Some("2023-09-20T09:00:00Z"),
+                None,
+                Some("yesterday"),
+            ]
+        );
+        let keys = ["-age".parse().unwrap()];
+        sort_by_keys(&mut mirrors, &keys, CountryOrder::Name);
+        let last_syncs: Vec<_> = mirrors.iter().map(|m| m.last_sync.as_deref()).collect();
+        assert_eq!(
+            &last_syncs[..2],
+            [Some("2023-09-20T09:00:00Z"), Some("2023-09-20T11:00:00Z")]
+        );
+    }
+}
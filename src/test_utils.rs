@@ -0,0 +1,110 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use url::Url;
+
+use crate::{Mirror, MirrorStatus};
+
+/// A fully valid mirror to derive test fixtures from.
+pub(crate) fn mirror() -> Mirror {
+    Mirror {
+        url: Url::parse("https://mirror.example.com/archlinux/").unwrap(),
+        protocol: "https".to_string(),
+        last_sync: None,
+        completion_pct: 1.0,
+        delay: None,
+        duration_avg: None,
+        duration_stddev: None,
+        score: None,
+        active: true,
+        country: "Germany".to_string(),
+        country_code: "DE".to_string(),
+        isos: true,
+        ipv4: true,
+        ipv6: true,
+        details: String::new(),
+        rate: None,
+    }
+}
+
+/// A mirror status without mirrors, as served to tests of its retrieval.
+pub(crate) fn status() -> MirrorStatus {
+    MirrorStatus {
+        cutoff: 3600,
+        ..Default::default()
+    }
+}
+
+/// A path in the temporary directory for the test `name`, unique to this
+/// process, so that tests never touch the user cache or each other's files.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("reflector-rs-{}-{name}", std::process::id()))
+}
+
+/// Build a raw HTTP response with `status`, extra `headers` and `body`.
+pub(crate) fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {status}\r\n");
+    for (name, value) in headers {
+        response += &format!("{name}: {value}\r\n");
+    }
+    response += &format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Build a raw `200 OK` HTTP response carrying `body` as JSON.
+pub(crate) fn json_response(body: &[u8]) -> Vec<u8> {
+    response("200 OK", &[("Content-Type", "application/json")], body)
+}
+
+/// Serve `responses` on a local port, one per connection and in order.
+///
+/// Returns the base URL of the server.
+pub(crate) async fn serve(responses: Vec<Vec<u8>>) -> Url {
+    serve_recording(responses).await.0
+}
+
+/// Like [`serve`], but also return the heads of the requests received so
+/// far, in order.
+pub(crate) async fn serve_recording(responses: Vec<Vec<u8>>) -> (Url, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    tokio::spawn(async move {
+        for response in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            received
+                .lock()
+                .unwrap()
+                .push(String::from_utf8_lossy(&request).into_owned());
+            let _ = stream.write_all(&response).await;
+            let _ = stream.shutdown().await;
+        }
+    });
+    (url, requests)
+}
+
+/// Serve `status` once as JSON, returning the URL of `path` on the server.
+pub(crate) async fn serve_status(status: &MirrorStatus, path: &str) -> Url {
+    let body = serde_json::to_vec(status).unwrap();
+    serve(vec![json_response(&body)]).await.join(path).unwrap()
+}
@@ -0,0 +1,161 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of days between the Unix epoch and the given proleptic Gregorian date.
+///
+/// Returns `None` if the number of days overflows.
+fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    let year = if month <= 2 {
+        year.checked_sub(1)?
+    } else {
+        year
+    };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era.checked_mul(146097)?
+        .checked_add(day_of_era)?
+        .checked_sub(719468)
+}
+
+/// The proleptic Gregorian date of the given number of days since the Unix
+/// epoch, as `(year, month, day)`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Format `time` as `2023-09-20 10:08:33 UTC`, truncated to the second.
+pub(crate) fn format_utc(time: SystemTime) -> String {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs_f64().ceil() as i64),
+    };
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Parse an RFC 3339 timestamp such as `2023-09-20T10:26:05.921Z`, as used
+/// by the Arch Linux Mirror Status API.
+///
+/// Returns `None` if `s` is not a valid timestamp.
+pub(crate) fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+
+    let mut date = date.splitn(3, '-');
+    let year: i64 = date.next()?.parse().ok()?;
+    let month: i64 = date.next()?.parse().ok()?;
+    let day: i64 = date.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let (time, offset) = time.split_at(time.rfind(['+', '-'])?);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        let hours: i64 = hours.parse().ok()?;
+        let minutes: i64 = minutes.parse().ok()?;
+        if hours > 23 || minutes > 59 {
+            return None;
+        }
+        (time, sign * (hours * 3600 + minutes * 60))
+    };
+
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':');
+    let hour: i64 = time.next()?.parse().ok()?;
+    let minute: i64 = time.next()?.parse().ok()?;
+    let second: i64 = time.next()?.parse().ok()?;
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let nanos = if fraction.is_empty() {
+        0
+    } else {
+        if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        format!("{:0<9.9}", fraction).parse().ok()?
+    };
+
+    let secs = days_from_civil(year, month, day)?
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second - offset)?;
+    let since_epoch = Duration::from_nanos(nanos);
+    if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs as u64) + since_epoch)
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()) - since_epoch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(
+            parse_rfc3339("2023-09-20T10:08:33Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(1695204513))
+        );
+        assert_eq!(
+            parse_rfc3339("2023-09-20T10:26:05.921Z"),
+            Some(UNIX_EPOCH + Duration::from_millis(1695205565921))
+        );
+        assert_eq!(
+            parse_rfc3339("2023-09-20T12:08:33+02:00"),
+            parse_rfc3339("2023-09-20T10:08:33Z")
+        );
+        assert_eq!(
+            parse_rfc3339("1969-12-31T23:59:59Z"),
+            UNIX_EPOCH.checked_sub(Duration::from_secs(1))
+        );
+        assert_eq!(parse_rfc3339("2023-13-20T10:08:33Z"), None);
+        assert_eq!(parse_rfc3339("not a timestamp"), None);
+    }
+
+    #[test]
+    fn test_parse_rfc3339_out_of_range() {
+        assert_eq!(days_from_civil(i64::MAX, 12, 31), None);
+        assert_eq!(days_from_civil(i64::MIN, 1, 1), None);
+        assert_eq!(parse_rfc3339("9223372036854775807-12-31T23:59:59Z"), None);
+        assert_eq!(parse_rfc3339("-9223372036854775808-01-01T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("99999999999999-01-01T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2023-09-20T10:08:33+9999999999999:00"), None);
+    }
+
+    #[test]
+    fn test_format_utc() {
+        for timestamp in [
+            "2023-09-20T10:08:33Z",
+            "2000-02-29T23:59:59Z",
+            "1969-12-31T00:00:01Z",
+        ] {
+            let formatted = format_utc(parse_rfc3339(timestamp).unwrap());
+            assert_eq!(formatted, timestamp.replace('T', " ").replace('Z', " UTC"));
+        }
+        assert_eq!(
+            format_utc(parse_rfc3339("2023-09-20T10:26:05.921Z").unwrap()),
+            "2023-09-20 10:26:05 UTC"
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use clap::Parser;
+use reflector_rs::{select_mirrors, Filters, Mirror, MirrorStatus, SortType};
+use url::Url;
+
+fn mirror(url: &str, country_code: &str, score: f64) -> Mirror {
+    Mirror {
+        url: Url::parse(url).unwrap(),
+        protocol: Url::parse(url).unwrap().scheme().to_string(),
+        last_sync: Some("2023-09-20T10:00:00Z".to_string()),
+        completion_pct: 1.0,
+        delay: Some(600),
+        duration_avg: None,
+        duration_stddev: None,
+        score: Some(score),
+        active: true,
+        country: country_code.to_string(),
+        country_code: country_code.to_string(),
+        isos: true,
+        ipv4: true,
+        ipv6: false,
+        details: String::new(),
+        rate: None,
+    }
+}
+
+#[test]
+fn test_select_mirrors() {
+    let status = MirrorStatus {
+        last_check: "2023-09-20T10:08:33Z".to_string(),
+        urls: vec![
+            mirror("https://de1.example.com/archlinux/", "DE", 3.0),
+            mirror("http://de2.example.com/archlinux/", "DE", 1.0),
+            mirror("https://fr.example.com/archlinux/", "FR", 0.5),
+            mirror("https://de3.example.com/archlinux/", "DE", 2.0),
+            mirror("rsync://de4.example.com/archlinux/", "DE", 0.1),
+        ],
+        ..Default::default()
+    };
+    let filters = Filters::parse_from([
+        "reflector-rs",
+        "--country",
+        "de",
+        "--protocols",
+        "http,https",
+        "--number",
+        "2",
+    ]);
+    let urls: Vec<_> = select_mirrors(status, &filters, Some(SortType::Score))
+        .unwrap()
+        .into_iter()
+        .map(|mirror| mirror.url.to_string())
+        .collect();
+    assert_eq!(
+        urls,
+        [
+            "http://de2.example.com/archlinux/",
+            "https://de3.example.com/archlinux/"
+        ]
+    );
+}
+
+#[test]
+fn test_select_mirrors_max_per_asn() {
+    let status = MirrorStatus {
+        urls: vec![
+            mirror("https://a.example.com/archlinux/", "DE", 1.0),
+            mirror("https://b.example.com/archlinux/", "DE", 2.0),
+            mirror("https://mirror.example.org/archlinux/", "DE", 3.0),
+            mirror("https://c.example.com/archlinux/", "DE", 0.5),
+        ],
+        ..Default::default()
+    };
+    let filters = Filters::parse_from(["reflector-rs", "--max-per-asn", "2"]);
+    let urls: Vec<_> = select_mirrors(status, &filters, Some(SortType::Score))
+        .unwrap()
+        .into_iter()
+        .map(|mirror| mirror.url.to_string())
+        .collect();
+    assert_eq!(
+        urls,
+        [
+            "https://a.example.com/archlinux/",
+            "https://b.example.com/archlinux/",
+            "https://mirror.example.org/archlinux/",
+        ]
+    );
+}
+
+#[test]
+fn test_select_mirrors_invalid_regex() {
+    let filters = Filters::parse_from(["reflector-rs", "--include", "("]);
+    assert!(select_mirrors(MirrorStatus::default(), &filters, None).is_err());
+}